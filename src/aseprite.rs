@@ -0,0 +1,218 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use crate::convert::{self, AnimLayout, TICKS_PER_SECOND};
+use crate::obj::Palette;
+
+// See https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md
+const FILE_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+const HEADER_SIZE: usize = 128;
+const FRAME_HEADER_SIZE: usize = 16;
+const COLOR_DEPTH_INDEXED: u16 = 8;
+
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_TAGS: u16 = 0x2018;
+const CHUNK_PALETTE: u16 = 0x2019;
+
+const CEL_RAW: u16 = 0;
+
+struct Cel {
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+}
+
+struct AseFrame {
+    duration_ms: u16,
+    cel: Option<Cel>,
+}
+
+struct AseTag {
+    name: String,
+    from: u16,
+    to: u16,
+    /// 0 repeats forever.
+    repeat: u16,
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_u16::<LittleEndian>(s.len() as u16)?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: u16, data: &[u8]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(6 + data.len() as u32)?;
+    w.write_u16::<LittleEndian>(kind)?;
+    w.write_all(data)
+}
+
+fn layer_chunk(name: &str) -> io::Result<Vec<u8>> {
+    let mut c = Vec::new();
+    c.write_u16::<LittleEndian>(0x3)?; // visible | editable
+    c.write_u16::<LittleEndian>(0)?; // normal layer
+    c.write_u16::<LittleEndian>(0)?; // child level
+    c.write_u16::<LittleEndian>(0)?;
+    c.write_u16::<LittleEndian>(0)?;
+    c.write_u16::<LittleEndian>(0)?; // blend mode
+    c.write_u8(255)?; // opacity
+    c.write_all(&[0; 3])?;
+    write_string(&mut c, name)?;
+    Ok(c)
+}
+
+fn palette_chunk(palette: &Palette) -> io::Result<Vec<u8>> {
+    let mut c = Vec::new();
+    c.write_u32::<LittleEndian>(palette.colors.len() as u32)?;
+    c.write_u32::<LittleEndian>(0)?;
+    c.write_u32::<LittleEndian>(palette.colors.len() as u32 - 1)?;
+    c.write_all(&[0; 8])?;
+    for color in palette.colors.iter() {
+        c.write_u16::<LittleEndian>(0)?; // no name
+        c.write_all(&color.0)?;
+    }
+    Ok(c)
+}
+
+fn cel_chunk(cel: &Cel) -> io::Result<Vec<u8>> {
+    let mut c = Vec::new();
+    c.write_u16::<LittleEndian>(0)?; // layer
+    c.write_i16::<LittleEndian>(cel.x)?;
+    c.write_i16::<LittleEndian>(cel.y)?;
+    c.write_u8(255)?; // opacity
+    c.write_u16::<LittleEndian>(CEL_RAW)?;
+    c.write_i16::<LittleEndian>(0)?; // z-index
+    c.write_all(&[0; 5])?;
+    c.write_u16::<LittleEndian>(cel.width)?;
+    c.write_u16::<LittleEndian>(cel.height)?;
+    c.write_all(&cel.pixels)?;
+    Ok(c)
+}
+
+fn tags_chunk(tags: &[AseTag]) -> io::Result<Vec<u8>> {
+    let mut c = Vec::new();
+    c.write_u16::<LittleEndian>(tags.len() as u16)?;
+    c.write_all(&[0; 8])?;
+    for tag in tags.iter() {
+        c.write_u16::<LittleEndian>(tag.from)?;
+        c.write_u16::<LittleEndian>(tag.to)?;
+        c.write_u8(0)?; // forward
+        c.write_u16::<LittleEndian>(tag.repeat)?;
+        c.write_all(&[0; 6])?;
+        c.write_all(&[0, 0, 0])?; // deprecated tag color
+        c.write_u8(0)?;
+        write_string(&mut c, &tag.name)?;
+    }
+    Ok(c)
+}
+
+fn encode(width: u16, height: u16, transparent_index: u8, palette: &Palette, frames: &[AseFrame], tags: &[AseTag]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        let mut chunks = Vec::new();
+        if i == 0 {
+            chunks.push((CHUNK_PALETTE, palette_chunk(palette)?));
+            chunks.push((CHUNK_LAYER, layer_chunk("Sprite")?));
+            if !tags.is_empty() {
+                chunks.push((CHUNK_TAGS, tags_chunk(tags)?));
+            }
+        }
+        if let Some(cel) = &frame.cel {
+            chunks.push((CHUNK_CEL, cel_chunk(cel)?));
+        }
+
+        let mut data = Vec::new();
+        for (kind, chunk) in chunks.iter() {
+            write_chunk(&mut data, *kind, chunk)?;
+        }
+
+        body.write_u32::<LittleEndian>((FRAME_HEADER_SIZE + data.len()) as u32)?;
+        body.write_u16::<LittleEndian>(FRAME_MAGIC)?;
+        body.write_u16::<LittleEndian>(chunks.len() as u16)?;
+        body.write_u16::<LittleEndian>(frame.duration_ms)?;
+        body.write_all(&[0; 2])?;
+        body.write_u32::<LittleEndian>(chunks.len() as u32)?;
+        body.write_all(&data)?;
+    }
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+    out.write_u32::<LittleEndian>((HEADER_SIZE + body.len()) as u32)?;
+    out.write_u16::<LittleEndian>(FILE_MAGIC)?;
+    out.write_u16::<LittleEndian>(frames.len() as u16)?;
+    out.write_u16::<LittleEndian>(width)?;
+    out.write_u16::<LittleEndian>(height)?;
+    out.write_u16::<LittleEndian>(COLOR_DEPTH_INDEXED)?;
+    out.write_u32::<LittleEndian>(1)?; // layer opacity is valid
+    out.write_u16::<LittleEndian>(frames.first().map_or(100, |f| f.duration_ms))?;
+    out.write_u32::<LittleEndian>(0)?;
+    out.write_u32::<LittleEndian>(0)?;
+    out.write_u8(transparent_index)?;
+    out.write_all(&[0; 3])?;
+    out.write_u16::<LittleEndian>(palette.colors.len() as u16)?;
+    out.write_u8(1)?; // pixel width
+    out.write_u8(1)?; // pixel height
+    out.write_i16::<LittleEndian>(0)?;
+    out.write_i16::<LittleEndian>(0)?;
+    out.write_u16::<LittleEndian>(16)?;
+    out.write_u16::<LittleEndian>(16)?;
+    out.write_all(&[0; 84])?;
+    out.extend(body);
+    Ok(out)
+}
+
+/// Writes ObjDefs laid out by `convert::layout_anim`, given with their
+/// indices, as one indexed-color .aseprite file: the palette with its
+/// alpha bits, the frames of every ObjDef one after another on a shared
+/// canvas, and an `objdef_NNNN` tag over each ObjDef's frames. This is how
+/// an ObjInfo's ObjDefs are kept together; tags loop when `looping` is set
+/// and play once otherwise. Nothing is written if no frame draws anything.
+pub fn write_anim_aseprite(path: &Path, anims: &[(usize, &AnimLayout)], palette: &Palette, looping: bool) -> Result<()> {
+    let anims: Vec<_> = anims.iter()
+        .filter(|(_, layout)| !layout.frames.is_empty() && layout.width > 0 && layout.height > 0)
+        .collect();
+    if anims.is_empty() {
+        return Ok(());
+    }
+
+    let left = anims.iter().map(|(_, l)| l.origin.0).min().unwrap();
+    let top = anims.iter().map(|(_, l)| l.origin.1).min().unwrap();
+    let right = anims.iter().map(|(_, l)| l.origin.0 + l.width as i32).max().unwrap();
+    let bottom = anims.iter().map(|(_, l)| l.origin.1 + l.height as i32).max().unwrap();
+
+    let transparent_index = palette.background_index();
+
+    let mut frames = Vec::new();
+    let mut tags = Vec::new();
+    for (index, layout) in anims.iter() {
+        let (dx, dy) = ((layout.origin.0 - left) as u32, (layout.origin.1 - top) as u32);
+        let from = frames.len() as u16;
+        for frame in layout.frames.iter() {
+            let decomp = &layout.decompressed[&frame.spi_idx];
+            let (spi_left, spi_top) = convert::spi_origin(decomp);
+            let cel = convert::render_spi_indexed(decomp, transparent_index)
+                .map(|img| Cel {
+                    x: (dx + frame.x + spi_left) as i16,
+                    y: (dy + frame.y + spi_top) as i16,
+                    width: img.width() as u16,
+                    height: img.height() as u16,
+                    pixels: img.into_raw(),
+                });
+            let duration_ms = (frame.delay as u32 * 1000 / TICKS_PER_SECOND) as u16;
+            frames.push(AseFrame { duration_ms, cel });
+        }
+        tags.push(AseTag {
+            name: format!("objdef_{:0>4}", index),
+            from,
+            to: frames.len() as u16 - 1,
+            repeat: if looping { 0 } else { 1 },
+        });
+    }
+
+    let data = encode((right - left) as u16, (bottom - top) as u16, transparent_index, palette, &frames, &tags)?;
+    fs::write(path, data).with_context(|| format!("Unable to write {}", path.display()))
+}
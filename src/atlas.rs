@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use anyhow::{Context, Result};
+use image::{GenericImageView, RgbaImage};
+use serde::{Serialize, Serializer};
+use crate::cache::SpiCache;
+use crate::convert::{self, TICKS_PER_SECOND};
+use crate::obj::{PaletteMap, Spis, Tables};
+
+// Gap left between packed images so filtering doesn't bleed neighbours in.
+const PADDING: u32 = 1;
+
+/// One named image to pack. `offset` is where the image sits within a
+/// canvas of `source_size`, e.g. a frame's position within its animation.
+pub struct Sprite {
+    pub name: String,
+    pub image: RgbaImage,
+    pub offset: (u32, u32),
+    pub source_size: (u32, u32),
+    pub duration: Option<u32>,
+}
+
+/// A run of consecutive sprites making up one animation.
+pub struct Tag {
+    pub name: String,
+    pub sprites: Range<usize>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Serialize)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AtlasFrame {
+    frame: Rect,
+    rotated: bool,
+    trimmed: bool,
+    sprite_source_size: Rect,
+    source_size: Size,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u32>,
+}
+
+/// Frames keyed by name, in packing order.
+struct Frames(Vec<(String, AtlasFrame)>);
+
+impl Serialize for Frames {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(name, frame)| (name, frame)))
+    }
+}
+
+#[derive(Serialize)]
+struct FrameTag {
+    name: String,
+    from: usize,
+    to: usize,
+    direction: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    app: &'static str,
+    version: &'static str,
+    image: String,
+    format: &'static str,
+    size: Size,
+    scale: &'static str,
+    frame_tags: Vec<FrameTag>,
+}
+
+#[derive(Serialize)]
+struct AtlasJson {
+    frames: Frames,
+    meta: Meta,
+}
+
+/// Bounding box of the non-transparent pixels, or a single pixel for
+/// images with none.
+fn trim(image: &RgbaImage) -> Rect {
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, px) in image.enumerate_pixels() {
+        if px.0[3] != 0 {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+    }
+
+    if left == u32::MAX {
+        return Rect { x: 0, y: 0, w: 1, h: 1 };
+    }
+    Rect { x: left, y: top, w: right - left, h: bottom - top }
+}
+
+struct Page {
+    width: u32,
+    height: u32,
+    shelf_y: u32,
+    shelf_x: u32,
+    shelf_height: u32,
+}
+
+impl Page {
+    fn new() -> Self {
+        Page { width: 0, height: 0, shelf_y: 0, shelf_x: 0, shelf_height: 0 }
+    }
+
+    /// Places a w*h rectangle on the current shelf, or on a new shelf below
+    /// it, returning None if the page is full.
+    fn place(&mut self, w: u32, h: u32, max_size: u32) -> Option<(u32, u32)> {
+        if self.shelf_x + w > max_size && self.shelf_x > 0 {
+            self.shelf_y += self.shelf_height + PADDING;
+            self.shelf_x = 0;
+            self.shelf_height = 0;
+        }
+        if self.shelf_y + h > max_size && self.shelf_y > 0 {
+            return None;
+        }
+
+        let pos = (self.shelf_x, self.shelf_y);
+        self.shelf_x += w + PADDING;
+        self.shelf_height = self.shelf_height.max(h);
+        self.width = self.width.max(pos.0 + w);
+        self.height = self.height.max(pos.1 + h);
+        Some(pos)
+    }
+}
+
+/// Packs sprites into as many pages of at most `max_size` square as needed
+/// with shelf packing, tallest first, and writes `<prefix>_NN.png` and
+/// `<prefix>_NN.json` for each page into `outdir`. The JSON follows
+/// TexturePacker's "hash" format, with Aseprite's per-frame durations and
+/// frame tags. Identical images are stored once. Returns the page count,
+/// or an error if a trimmed sprite is larger than a page.
+pub fn write_atlases(outdir: &Path, prefix: &str, sprites: &[Sprite], tags: &[Tag], max_size: u32) -> Result<usize> {
+    // Trim and deduplicate.
+    let mut unique: Vec<RgbaImage> = Vec::new();
+    let mut seen: HashMap<(u32, u32, Vec<u8>), usize> = HashMap::new();
+    let mut trimmed = Vec::new();
+    for sprite in sprites.iter() {
+        let rect = trim(&sprite.image);
+        if rect.w > max_size || rect.h > max_size {
+            return Err(anyhow!("{} is {}x{} after trimming, larger than the {}x{} atlas size",
+                               sprite.name, rect.w, rect.h, max_size, max_size));
+        }
+        let cropped = sprite.image.view(rect.x, rect.y, rect.w, rect.h).to_image();
+        let key = (rect.w, rect.h, cropped.as_raw().clone());
+        let id = *seen.entry(key).or_insert_with(|| {
+            unique.push(cropped);
+            unique.len() - 1
+        });
+        trimmed.push((rect, id));
+    }
+
+    let mut order: Vec<usize> = (0..unique.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&unique[a], &unique[b]);
+        b.height().cmp(&a.height()).then(b.width().cmp(&a.width()))
+    });
+
+    let mut pages = vec![Page::new()];
+    let mut placement = vec![(0, 0, 0); unique.len()];
+    for id in order.into_iter() {
+        let (w, h) = unique[id].dimensions();
+        let pos = match pages.last_mut().unwrap().place(w, h, max_size) {
+            Some(pos) => pos,
+            None => {
+                pages.push(Page::new());
+                pages.last_mut().unwrap().place(w, h, max_size).unwrap()
+            }
+        };
+        placement[id] = (pages.len() - 1, pos.0, pos.1);
+    }
+
+    for (page_no, page) in pages.iter().enumerate() {
+        let image_name = format!("{}_{:02}.png", prefix, page_no);
+        let mut atlas = RgbaImage::new(page.width.max(1), page.height.max(1));
+        for (id, image) in unique.iter().enumerate() {
+            let (p, x, y) = placement[id];
+            if p == page_no {
+                image::imageops::replace(&mut atlas, image, x as i64, y as i64);
+            }
+        }
+        atlas.save(outdir.join(&image_name))?;
+
+        let mut frames = Vec::new();
+        let mut frame_numbers = HashMap::new();
+        for (i, (sprite, (rect, id))) in sprites.iter().zip(trimmed.iter()).enumerate() {
+            let (p, x, y) = placement[*id];
+            if p != page_no {
+                continue;
+            }
+            frame_numbers.insert(i, frames.len());
+            frames.push((sprite.name.clone(), AtlasFrame {
+                frame: Rect { x, y, w: rect.w, h: rect.h },
+                rotated: false,
+                trimmed: rect.w != sprite.source_size.0 || rect.h != sprite.source_size.1,
+                sprite_source_size: Rect { x: sprite.offset.0 + rect.x, y: sprite.offset.1 + rect.y, w: rect.w, h: rect.h },
+                source_size: Size { w: sprite.source_size.0, h: sprite.source_size.1 },
+                duration: sprite.duration,
+            }));
+        }
+
+        // Tags can only refer to frames on the same page.
+        let frame_tags = tags.iter()
+            .filter(|tag| !tag.sprites.is_empty() && tag.sprites.clone().all(|i| frame_numbers.contains_key(&i)))
+            .map(|tag| FrameTag {
+                name: tag.name.clone(),
+                from: frame_numbers[&tag.sprites.start],
+                to: frame_numbers[&(tag.sprites.end - 1)],
+                direction: "forward",
+            })
+            .collect();
+
+        let json = AtlasJson {
+            frames: Frames(frames),
+            meta: Meta {
+                app: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                image: image_name,
+                format: "RGBA8888",
+                size: Size { w: atlas.width(), h: atlas.height() },
+                scale: "1",
+                frame_tags,
+            },
+        };
+        let path = outdir.join(format!("{}_{:02}.json", prefix, page_no));
+        fs::write(&path, serde_json::to_string_pretty(&json)?)
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+    }
+
+    Ok(pages.len())
+}
+
+/// Every SPI with something to draw, sprites and backgrounds alike, named
+/// like the files `spi0/` and `spi1/` get.
+pub fn spi_sprites(tables: &Tables, spis: &Spis, cache: &SpiCache, palettes: &PaletteMap) -> Result<Vec<Sprite>> {
+    let mut sprites = Vec::new();
+    for (i, spi) in spis.iter().enumerate() {
+        let spi = spi?;
+        let decomp = cache.get(spis, i)?;
+        if let Some(image) = convert::render_spi(&decomp, &tables.palettes[palettes.spis[i]]) {
+            let source_size = image.dimensions();
+            sprites.push(Sprite {
+                name: format!("{}_{:0>8}", spi.header.magic.to_lowercase(), i),
+                image,
+                offset: (0, 0),
+                source_size,
+                duration: None,
+            });
+        }
+    }
+    Ok(sprites)
+}
+
+/// Every drawable frame of the given ObjDefs, each positioned within its
+/// animation's bounds, with one tag per ObjDef.
+pub fn anim_sprites(tables: &Tables, spis: &Spis, cache: &SpiCache, defs: &[usize], palettes: &PaletteMap) -> Result<(Vec<Sprite>, Vec<Tag>)> {
+    let mut sprites = Vec::new();
+    let mut tags = Vec::new();
+    for &i in defs.iter() {
+        let def = tables.defs.get(i)
+            .ok_or_else(|| anyhow!("ObjDef {} out of range (max {})", i, tables.defs.len() as isize - 1))?;
+        let layout = convert::layout_anim(def, spis, cache)?;
+        let palette = &tables.palettes[palettes.defs[i]];
+
+        let start = sprites.len();
+        for frame in layout.frames.iter() {
+            let decomp = &layout.decompressed[&frame.spi_idx];
+            let image = match convert::render_spi(decomp, palette) {
+                Some(image) => image,
+                None => continue,
+            };
+            let (left, top) = convert::spi_origin(decomp);
+            sprites.push(Sprite {
+                name: format!("anim_{:0>8}_{:0>2}", i, frame.index),
+                image,
+                offset: (frame.x + left, frame.y + top),
+                source_size: (layout.width, layout.height),
+                duration: Some(frame.delay as u32 * 1000 / TICKS_PER_SECOND),
+            });
+        }
+        tags.push(Tag { name: format!("anim_{:0>8}", i), sprites: start..sprites.len() });
+    }
+    Ok((sprites, tags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn sprite(name: &str, w: u32, h: u32) -> Sprite {
+        Sprite { name: name.to_string(), image: RgbaImage::from_pixel(w, h, Rgba([1, 2, 3, 255])), offset: (0, 0), source_size: (w, h), duration: None }
+    }
+
+    #[test]
+    fn oversized_sprite_is_an_error() {
+        let dir = std::env::temp_dir().join(format!("josette-atlas-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let sprites = [sprite("spi1_00000000", 8, 8), sprite("spi1_00000001", 20, 4)];
+        let err = write_atlases(&dir, "test", &sprites, &[], 16).unwrap_err().to_string();
+        assert!(err.contains("spi1_00000001 is 20x4"), "{}", err);
+        assert_eq!(write_atlases(&dir, "test", &sprites, &[], 32).unwrap(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use byteorder::{ByteOrder, BigEndian};
+use crate::convert;
+use crate::obj::Spis;
+use crate::spi::Spi;
+use crate::writeable::Writeable;
+
+// Distinguishes temporary files written at the same time by one process.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A cache file starts with the stream's length and hash, both big-endian.
+const ENTRY_HEADER_SIZE: usize = 16;
+
+/// Decompressed SPIs by index, shared by everything that renders them so
+/// each SPI is only decompressed once per run. Entries are kept until the
+/// cache is dropped.
+///
+/// With a directory, decompressed streams are also kept on disk between
+/// runs. Files there are named by a hash of the compressed container rather
+/// than its index, so a rebuilt or different ROM never picks up stale data,
+/// and carry the stream's length and hash so a damaged file is redone.
+#[derive(Default)]
+pub struct SpiCache {
+    memory: Mutex<HashMap<usize, Arc<Vec<u8>>>>,
+    dir: Option<PathBuf>,
+}
+
+impl SpiCache {
+    /// A cache that only lives in memory.
+    pub fn new() -> SpiCache {
+        SpiCache::default()
+    }
+
+    /// A cache that also reads and writes decompressed streams in `dir`.
+    pub fn with_dir(dir: &Path) -> Result<SpiCache> {
+        fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+        Ok(SpiCache { memory: Mutex::default(), dir: Some(dir.to_path_buf()) })
+    }
+
+    /// The decompressed stream of SPI `i`. Two threads asking for the same
+    /// SPI at once may both decompress it; either result is kept.
+    pub fn get(&self, spis: &Spis, i: usize) -> Result<Arc<Vec<u8>>> {
+        if let Some(decomp) = self.memory.lock().unwrap().get(&i) {
+            return Ok(decomp.clone());
+        }
+
+        let spi = spis.get(i)?;
+        let decomp = Arc::new(self.load(&spi)?);
+        self.memory.lock().unwrap().insert(i, decomp.clone());
+        Ok(decomp)
+    }
+
+    fn load(&self, spi: &Spi) -> Result<Vec<u8>> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return convert::decompress_spi(spi),
+        };
+
+        let path = dir.join(format!("{:016x}.bin", container_hash(spi)));
+        if let Some(decomp) = fs::read(&path).ok().and_then(read_entry) {
+            return Ok(decomp);
+        }
+
+        let decomp = convert::decompress_spi(spi)?;
+        let mut entry = vec![0; ENTRY_HEADER_SIZE];
+        BigEndian::write_u64(&mut entry[0..8], decomp.len() as u64);
+        BigEndian::write_u64(&mut entry[8..16], fnv1a(&decomp));
+        entry.extend_from_slice(&decomp);
+        // Written under a temporary name first so a concurrent run never
+        // reads a partial file.
+        let tmp = path.with_extension(format!("{}.{}.tmp", process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&tmp, &entry).with_context(|| format!("Unable to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Unable to write {}", path.display()))?;
+        Ok(decomp)
+    }
+}
+
+/// The stream in a cache file, if its length and hash check out.
+fn read_entry(mut entry: Vec<u8>) -> Option<Vec<u8>> {
+    if entry.len() < ENTRY_HEADER_SIZE {
+        return None;
+    }
+    let len = BigEndian::read_u64(&entry[0..8]);
+    let hash = BigEndian::read_u64(&entry[8..16]);
+    let decomp = entry.split_off(ENTRY_HEADER_SIZE);
+    (decomp.len() as u64 == len && fnv1a(&decomp) == hash).then_some(decomp)
+}
+
+/// 64-bit FNV-1a.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Hash of the container as stored in the ROM.
+fn container_hash(spi: &Spi) -> u64 {
+    let mut bytes = Vec::with_capacity(spi.byte_size());
+    spi.write(&mut bytes).unwrap();
+    fnv1a(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Profile;
+
+    #[test]
+    fn second_cache_reads_from_disk() {
+        // A header size the stream decodes past.
+        let mut spi = crate::compress::compress_spi0(&[3; 0x40]);
+        spi.header.u1 = 0x3f;
+        let mut rom = vec![0; 8];
+        spi.write(&mut rom).unwrap();
+        let profile = Profile { spi_offset_offset: 0, spi_base_offset: 8, spi_count: 1, ..Profile::wonder_project_j2() };
+        let spis = Spis::new(&profile, &rom);
+
+        let dir = std::env::temp_dir().join(format!("josette-cache-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let decomp = SpiCache::with_dir(&dir).unwrap().get(&spis, 0).unwrap();
+        assert_ne!(decomp.len(), spi.header.u1);
+
+        // Swap in a different stream; only a read from disk can return it.
+        let path = dir.join(format!("{:016x}.bin", container_hash(&spi)));
+        let mut entry = vec![0; ENTRY_HEADER_SIZE];
+        BigEndian::write_u64(&mut entry[0..8], 4);
+        BigEndian::write_u64(&mut entry[8..16], fnv1a(&[9; 4]));
+        entry.extend_from_slice(&[9; 4]);
+        fs::write(&path, &entry).unwrap();
+        assert_eq!(*SpiCache::with_dir(&dir).unwrap().get(&spis, 0).unwrap(), vec![9; 4]);
+
+        // A damaged file is decompressed again and rewritten.
+        fs::write(&path, &entry[..entry.len() - 1]).unwrap();
+        assert_eq!(SpiCache::with_dir(&dir).unwrap().get(&spis, 0).unwrap(), decomp);
+        assert_eq!(read_entry(fs::read(&path).unwrap()).as_ref(), Some(&*decomp));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use std::borrow::Cow;
+use crate::spi::{Spi, SpiHeader};
+
+// Back-references store (distance - 1) in 12 bits and (length - 3) in a
+// nibble, with 0xF meaning the length continues in extra bytes.
+const WINDOW_SIZE: usize = 0x1000;
+const MIN_MATCH: usize = 3;
+const MAX_CHAIN: usize = 256;
+
+struct FlagWriter {
+    bytes: Vec<u8>,
+    bit_no: u32,
+}
+
+impl FlagWriter {
+    fn new() -> Self {
+        FlagWriter { bytes: Vec::new(), bit_no: 8 }
+    }
+
+    fn push(&mut self, bit: bool) {
+        if self.bit_no == 8 {
+            self.bit_no = 0;
+            self.bytes.push(0);
+        }
+
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> self.bit_no;
+        }
+        self.bit_no += 1;
+    }
+}
+
+/// Hash chains over three-byte prefixes, used to find back-references.
+struct MatchFinder {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl MatchFinder {
+    const NONE: usize = usize::MAX;
+
+    fn new(len: usize) -> Self {
+        MatchFinder { head: vec![Self::NONE; 1 << 16], prev: vec![Self::NONE; len] }
+    }
+
+    fn hash(input: &[u8], pos: usize) -> usize {
+        let h = ((input[pos] as u32) << 16) | ((input[pos + 1] as u32) << 8) | (input[pos + 2] as u32);
+        (h.wrapping_mul(0x9E37_79B1) >> 16) as usize
+    }
+
+    fn insert(&mut self, input: &[u8], pos: usize) {
+        if pos + MIN_MATCH > input.len() {
+            return;
+        }
+        let h = Self::hash(input, pos);
+        self.prev[pos] = self.head[h];
+        self.head[h] = pos;
+    }
+
+    /// Returns the (distance, length) of the longest earlier run matching
+    /// the bytes at `pos`.
+    fn find(&self, input: &[u8], pos: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH > input.len() {
+            return best;
+        }
+
+        let mut candidate = self.head[Self::hash(input, pos)];
+        let mut steps = 0;
+        while candidate != Self::NONE && pos - candidate <= WINDOW_SIZE && steps < MAX_CHAIN {
+            let len = input[candidate..].iter()
+                .zip(input[pos..].iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.1 {
+                best = (pos - candidate, len);
+                if pos + len == input.len() {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+            steps += 1;
+        }
+
+        best
+    }
+}
+
+fn push_back_reference(bytes: &mut Vec<u8>, distance: usize, len: usize) {
+    let distance = distance - 1;
+    let a = len - MIN_MATCH;
+    let nibble = std::cmp::min(a, 0xF);
+    bytes.push(((nibble << 4) | (distance >> 8)) as u8);
+    bytes.push((distance & 0xFF) as u8);
+    if nibble == 0xF {
+        let mut rest = a - 0xF;
+        while rest >= 0xFF {
+            bytes.push(0xFF);
+            rest -= 0xFF;
+        }
+        bytes.push(rest as u8);
+    }
+}
+
+fn build_spi(magic: &str, decompressed_size: usize, flags: Vec<u8>, nibbles: Vec<u8>, bytes: Vec<u8>) -> Spi<'static> {
+    let header = SpiHeader {
+        magic: magic.to_string(),
+        u1: decompressed_size,
+        u2: flags.len(),
+        u3: nibbles.len(),
+        u4: bytes.len(),
+    };
+
+    let mut data = flags;
+    data.extend(nibbles);
+    data.extend(bytes);
+
+    Spi { header, data: Cow::Owned(data) }
+}
+
+pub fn compress_spi(magic: &str, input: &[u8]) -> Result<Spi<'static>> {
+    match magic {
+        "SPI0" => Ok(compress_spi0(input)),
+        "SPI1" => Ok(compress_spi1(input)),
+        magic => Err(anyhow!("Unknown SPI magic {:?}", magic))
+    }
+}
+
+/// Compresses a raw byte stream into an SPI0 container, the inverse of
+/// `convert::decompress_spi0`. SPI0 has no nibble stream, so every literal
+/// is stored as a whole byte.
+pub fn compress_spi0(input: &[u8]) -> Spi<'static> {
+    let mut flags = FlagWriter::new();
+    let mut bytes = Vec::new();
+
+    let mut finder = MatchFinder::new(input.len());
+    let mut pos = 0;
+    while pos < input.len() {
+        let (distance, len) = finder.find(input, pos);
+        if len >= MIN_MATCH {
+            flags.push(false);
+            push_back_reference(&mut bytes, distance, len);
+            for p in pos..pos + len {
+                finder.insert(input, p);
+            }
+            pos += len;
+            continue;
+        }
+
+        flags.push(true);
+        bytes.push(input[pos]);
+        finder.insert(input, pos);
+        pos += 1;
+    }
+
+    build_spi("SPI0", input.len(), flags.bytes, Vec::new(), bytes)
+}
+
+/// Compresses a decompressed SPI1 stream, the inverse of
+/// `convert::decompress_spi1`.
+pub fn compress_spi1(input: &[u8]) -> Spi<'static> {
+    let mut flags = FlagWriter::new();
+    let mut nibbles = Vec::new();
+    let mut is_other = false;
+    let mut bytes = Vec::new();
+
+    // Mirrors the 16-entry color cache the decompressor keeps. New bytes are
+    // written round-robin, starting from slot 0.
+    let mut palette = [0u8; 16];
+    for (i, color) in palette.iter_mut().enumerate() {
+        *color = i as u8;
+    }
+    let mut pal_offset = 0;
+
+    let mut finder = MatchFinder::new(input.len());
+    let mut pos = 0;
+    while pos < input.len() {
+        let (distance, len) = finder.find(input, pos);
+        if len >= MIN_MATCH {
+            flags.push(false);
+            push_back_reference(&mut bytes, distance, len);
+            for p in pos..pos + len {
+                finder.insert(input, p);
+            }
+            pos += len;
+            continue;
+        }
+
+        let byte = input[pos];
+        flags.push(true);
+        match palette.iter().position(|&c| c == byte) {
+            Some(color_index) => {
+                flags.push(false);
+                if !is_other {
+                    nibbles.push((color_index as u8) << 4);
+                } else {
+                    let last = nibbles.len() - 1;
+                    nibbles[last] |= color_index as u8;
+                }
+                is_other = !is_other;
+            },
+            None => {
+                flags.push(true);
+                bytes.push(byte);
+                palette[pal_offset & 0xF] = byte;
+                pal_offset += 1;
+            }
+        }
+        finder.insert(input, pos);
+        pos += 1;
+    }
+
+    build_spi("SPI1", input.len(), flags.bytes, nibbles, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{decompress_spi0, decompress_spi1};
+    use proptest::prelude::*;
+
+    fn bitmap_stream() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..0x2000),
+            prop::collection::vec(0u8..24, 0..0x4000),
+            prop::collection::vec((any::<u8>(), 1usize..0x300), 0..32)
+                .prop_map(|runs| runs.into_iter().flat_map(|(b, n)| std::iter::repeat_n(b, n)).collect()),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn spi0_roundtrip(input in bitmap_stream()) {
+            let spi = compress_spi0(&input);
+            prop_assert_eq!(spi.header.magic.as_str(), "SPI0");
+            prop_assert_eq!(spi.header.u1, input.len());
+            prop_assert_eq!(spi.header.u3, 0);
+            prop_assert_eq!(decompress_spi0(&spi).unwrap(), input);
+        }
+
+        #[test]
+        fn spi1_roundtrip(input in bitmap_stream()) {
+            let spi = compress_spi1(&input);
+            prop_assert_eq!(spi.header.magic.as_str(), "SPI1");
+            prop_assert_eq!(spi.header.u1, input.len());
+            prop_assert_eq!(spi.data.len(), spi.header.u2 + spi.header.u3 + spi.header.u4);
+            prop_assert_eq!(decompress_spi1(&spi).unwrap(), input);
+        }
+    }
+}
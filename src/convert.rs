@@ -1,53 +1,85 @@
-use image::{ImageBuffer, Rgba, RgbaImage, Pixel};
-use rgb::FromSlice;
+use image::{GrayImage, Luma, RgbaImage, Delay};
+use image::codecs::gif::{GifEncoder, Repeat};
 use anyhow::{Context, Result};
-use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use byteorder::{ByteOrder, BigEndian};
 use tribool::Tribool;
-use std::mem;
 use std::collections::HashMap;
-use crate::Args;
+use std::sync::Arc;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use serde::Serialize;
+use crate::cache::SpiCache;
 use crate::spi::Spi;
-use crate::obj::{Palette, ObjDef};
-use crate::writeable::Writeable;
-
-/*
-pub fn convert_spi0(spi: &Spi, index: u32) -> Result<()> {
-let mut out = File::create(format!("C:\\Users\\yuno\\Documents\\josette\\spi_{:02x}.spi", index))?;
-spi.write(&mut out)?;
-
-let (s3, s1, s2) = spi.slices();
-
-let mut bit_no = 0;
-let mut s1_offset = 0;
-let mut s2_offset = 0;
-let mut s3_offset = 0;
-let mut pal_offset = 0x10;
-let mut is_other = false;
-
-let mut output = Vec::new();
-let mut palette = [0u32; 16];
-for i in 0..16 {
-palette[i] = i as u32;
+use crate::obj::{Palette, ObjDef, Spis};
+
+pub fn decompress_spi(spi: &Spi) -> Result<Vec<u8>> {
+    match spi.header.magic.as_str() {
+        "SPI0" => decompress_spi0(spi),
+        "SPI1" => decompress_spi1(spi),
+        magic => Err(anyhow!("Unknown SPI magic {:?}", magic))
     }
+}
 
-    assert!(spi.header.magic == "SPI0");
+fn read_byte(s: &[u8], offset: &mut usize) -> Result<u8> {
+    let byte = *s.get(*offset).ok_or_else(|| anyhow!("Compressed stream ended early (at {:#x})", *offset))?;
+    *offset += 1;
+    Ok(byte)
+}
 
-    // printall(s1);
-    // printall(s2);
-    // printall(s3);
+fn copy_back_reference(s2: &[u8], s2_offset: &mut usize, output: &mut Vec<u8>) -> Result<()> {
+    let b = read_byte(s2, s2_offset)? as usize;
+    let c = read_byte(s2, s2_offset)? as usize;
+    let mut a = (b as u32) >> 4;
+    if a == 0xF {
+        loop {
+            let d = read_byte(s2, s2_offset)?;
+            a += d as u32;
+            if d != 0xFF {
+                break;
+            }
+        }
+    }
+    let distance = c + (b & 0xF) * 0x100 + 1;
+    if distance > output.len() {
+        return Err(anyhow!("Back-reference {} bytes before start of output (at {})", distance, output.len()));
+    }
+    let mut pos = output.len() - distance;
+    a += 3;
+    while a > 0 {
+        a -= 1;
+        // run-length encoding
+        output.push(output[pos]);
+        pos += 1;
+    }
+    Ok(())
+}
+
+pub fn decompress_spi0(spi: &Spi) -> Result<Vec<u8>> {
+    let (s3, _, s2) = spi.slices();
+
+    let mut bit_no = 0;
+    let mut s2_offset = 0;
+    let mut s3_offset = 0;
+
+    let mut output = Vec::new();
+
+    if spi.header.magic != "SPI0" {
+        return Err(anyhow!("Expected an SPI0 container, got {:?}", spi.header.magic));
+    }
 
     let mut test_found = || {
         if bit_no == 8 {
             bit_no = 0;
             s3_offset += 1;
-            if s3_offset >= s3.len() {
-                return Tribool::Indeterminate;
-            }
+        }
+        if s3_offset >= s3.len() {
+            return Tribool::Indeterminate;
         }
 
         let shift = bit_no & 0x1f;
         bit_no += 1;
-        // println!("test! {} {} {}", s3[s3_offset] & 0x80, shift, s3[s3_offset] & 0x80 >> shift);
         if (s3[s3_offset] & 0x80 >> shift) != 0 {
             Tribool::True
         }
@@ -57,53 +89,22 @@ palette[i] = i as u32;
     };
 
     while output.len() < spi.header.u1 {
-        // println!("header {} target {} output {} data {} {} {} {}", spi.header.u1, spi.header.u1, output.len(), spi.data.len(), s1.len(), s2.len(), s3.len());
         let found = test_found();
         if found.is_indeterminate() {
-            // println!("ranout");
             break;
         }
 
         if found.is_true() {
-            let byte = s2[s2_offset];
-            s2_offset += 1;
+            let byte = read_byte(s2, &mut s2_offset)?;
             output.push(byte);
         }
         else {
-            let mut a = (s2[s2_offset] as u32) >> 4;
-            let b = s2[s2_offset] as usize;
-            let c = s2[s2_offset + 1] as usize;
-            // println!("ff a b c {:02x} {:02x} {:02x}", a, b, c);
-            s2_offset += 2;
-            if a == 0xF {
-                while s2[s2_offset] == 0xFF {
-                    a += 0xFF;
-                    s2_offset += 1;
-                }
-                let d = s2[s2_offset];
-                s2_offset += 1;
-                a += d as u32;
-            }
-            let mut pos = output.len() - c - (b & 0xF) * 0x100 - 1;
-            a += 3;
-            // println!("finala {:02x}", a);
-            while a > 0 {
-                a -= 1;
-                // run-length encoding
-                // TODO sized buffer
-                // println!("len {} b {:02x} c {:02x} write {:02x}", output.len(), b, c, output[pos]);
-                output.push(output[pos]);
-                pos += 1;
-            }
+            copy_back_reference(s2, &mut s2_offset, &mut output)?;
         }
     }
 
-    // println!("done");
-
-    // write_files(&output, &buffer, pos, 32, 32)
-    Ok(())
+    Ok(output)
 }
-*/
 
 pub fn decompress_spi1(spi: &Spi) -> Result<Vec<u8>> {
     let (s3, s1, s2) = spi.slices();
@@ -117,11 +118,13 @@ pub fn decompress_spi1(spi: &Spi) -> Result<Vec<u8>> {
 
     let mut output = Vec::new();
     let mut palette = [0u32; 16];
-    for i in 0..16 {
-        palette[i] = i as u32;
+    for (i, color) in palette.iter_mut().enumerate() {
+        *color = i as u32;
     }
 
-    assert!(spi.header.magic == "SPI1");
+    if spi.header.magic != "SPI1" {
+        return Err(anyhow!("Expected an SPI1 container, got {:?}", spi.header.magic));
+    }
 
     // printall(s1);
     // printall(s2);
@@ -131,9 +134,9 @@ pub fn decompress_spi1(spi: &Spi) -> Result<Vec<u8>> {
         if bit_no == 8 {
             bit_no = 0;
             s3_offset += 1;
-            if s3_offset >= s3.len() {
-                return Tribool::Indeterminate;
-            }
+        }
+        if s3_offset >= s3.len() {
+            return Tribool::Indeterminate;
         }
 
         let shift = bit_no & 0x1f;
@@ -158,8 +161,7 @@ pub fn decompress_spi1(spi: &Spi) -> Result<Vec<u8>> {
         if found.is_true() {
             let byte = match test_found() {
                 Tribool::True => {
-                    let it = s2[s2_offset];
-                    s2_offset += 1;
+                    let it = read_byte(s2, &mut s2_offset)?;
                     let color_index = (pal_offset & 0xF) as usize;
                     palette[color_index] = it as u32;
                     pal_offset += 1;
@@ -170,10 +172,10 @@ pub fn decompress_spi1(spi: &Spi) -> Result<Vec<u8>> {
                     // println!("tf s1[{:02x}]={:02x}", s1_offset, s1[s1_offset]);
                     let color_index = if !is_other {
                         is_other = true;
-                        (s1[s1_offset] >> 4) as usize
+                        let mut peek = s1_offset;
+                        (read_byte(s1, &mut peek)? >> 4) as usize
                     } else {
-                        let thing = s1[s1_offset];
-                        s1_offset += 1;
+                        let thing = read_byte(s1, &mut s1_offset)?;
                         is_other = false;
                         (thing & 0xF) as usize
                     };
@@ -181,148 +183,469 @@ pub fn decompress_spi1(spi: &Spi) -> Result<Vec<u8>> {
                     // println!("tf col={:02x}", color_index);
                     palette[color_index] as u8
                 },
-                _ => unreachable!()
+                _ => return Err(anyhow!("Flag stream ended in the middle of a literal"))
             };
 
             output.push(byte);
         }
         else {
-            let mut a = (s2[s2_offset] as u32) >> 4;
-            let b = s2[s2_offset] as usize;
-            let c = s2[s2_offset + 1] as usize;
-            // println!("ff a b c {:02x} {:02x} {:02x}", a, b, c);
-            s2_offset += 2;
-            if a == 0xF {
-                while s2[s2_offset] == 0xFF {
-                    a += 0xFF;
-                    s2_offset += 1;
-                }
-                let d = s2[s2_offset];
-                s2_offset += 1;
-                a += d as u32;
-            }
-            let mut pos = output.len() - c - (b & 0xF) * 0x100 - 1;
-            a += 3;
-            // println!("finala {:02x}", a);
-            while a > 0 {
-                a -= 1;
-                // run-length encoding
-                // TODO sized buffer
-                // println!("len {} b {:02x} c {:02x} write {:02x}", output.len(), b, c, output[pos]);
-                output.push(output[pos]);
-                pos += 1;
-            }
+            copy_back_reference(s2, &mut s2_offset, &mut output)?;
         }
     }
 
     Ok(output)
 }
 
-fn get_spi_size(spi: &Spi, decompressed: &[u8]) -> (u32, u32) {
-    let mut total_width = 0;
-    let mut total_height = 0;
-
-    let mut cur = decompressed;
-    let mut i = 0;
-    while cur.len() > 2 {
-        let offset_x = BigEndian::read_u16(&cur[0..2]);
-        let offset_y = BigEndian::read_u16(&cur[2..4]);
-        let width = BigEndian::read_u16(&cur[4..6]);
-        let height = BigEndian::read_u16(&cur[6..8]);
-
-        let offset = (width * height) as usize;
-        total_width = std::cmp::max(total_width, offset_x + width);
-        total_height = std::cmp::max(total_height, offset_y + height);
-        cur = &cur[8+offset..];
-
-        i += 1;
-    }
-
-    (total_width as u32, total_height as u32)
+/// One sub-rectangle of a decompressed SPI. `offset` is where its header
+/// starts in the decompressed stream.
+struct SpiChunk<'a> {
+    offset: usize,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    pixels: &'a [u8],
 }
 
-fn write_spi_partial(img: &mut RgbaImage, spi: &Spi, decompressed: &[u8], palette: &Palette, px: u32, py: u32) {
+/// The chunks of a decompressed SPI, stopping at the first truncated one.
+fn spi_chunks(decompressed: &[u8]) -> impl Iterator<Item = SpiChunk<'_>> {
     let mut cur = decompressed;
-    let mut i = 0;
-    while cur.len() > 2 {
-        let offset_x = BigEndian::read_u16(&cur[0..2]) as u32;
-        let offset_y = BigEndian::read_u16(&cur[2..4]) as u32;
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        if cur.len() < 8 {
+            return None;
+        }
+        let x = BigEndian::read_u16(&cur[0..2]) as u32;
+        let y = BigEndian::read_u16(&cur[2..4]) as u32;
         let width = BigEndian::read_u16(&cur[4..6]) as u32;
         let height = BigEndian::read_u16(&cur[6..8]) as u32;
-        let offset = (width * height) as usize;
-        let bitmap_part = &cur[8..8+offset];
-
-        for (i, by) in bitmap_part.iter().enumerate() {
-            let x = (i as u32 % width);
-            let y = (i as u32 / width);
-            let col = palette.colors[*by as usize];
-            img.put_pixel(offset_x + x + px, offset_y + y + py, col);
+
+        let size = width as usize * height as usize;
+        if cur.len() < 8 + size {
+            return None;
         }
+        let chunk = SpiChunk { offset, x, y, width, height, pixels: &cur[8..8+size] };
+        cur = &cur[8+size..];
+        offset += 8 + size;
+        Some(chunk)
+    })
+}
+
+fn get_spi_size(decompressed: &[u8]) -> (u32, u32) {
+    spi_chunks(decompressed).fold((0, 0), |(w, h), chunk| {
+        (std::cmp::max(w, chunk.x + chunk.width), std::cmp::max(h, chunk.y + chunk.height))
+    })
+}
+
+/// Calls `f(x, y, index)` for every pixel of every chunk in a decompressed
+/// SPI.
+fn for_each_spi_pixel<F: FnMut(u32, u32, u8)>(decompressed: &[u8], mut f: F) {
+    for chunk in spi_chunks(decompressed) {
+        for (i, by) in chunk.pixels.iter().enumerate() {
+            let x = i as u32 % chunk.width;
+            let y = i as u32 / chunk.width;
+            f(chunk.x + x, chunk.y + y, *by);
+        }
+    }
+}
+
+fn write_spi_partial(img: &mut RgbaImage, decompressed: &[u8], palette: &Palette, px: u32, py: u32) {
+    for_each_spi_pixel(decompressed, |x, y, by| {
+        img.put_pixel(x + px, y + py, palette.colors[by as usize]);
+    });
+}
+
+/// Backgrounds are made of many chunks placed in screen or map coordinates
+/// rather than around a sprite's origin; their first chunk starts past the
+/// 256-pixel sprite space.
+pub fn is_background(decompressed: &[u8]) -> bool {
+    decompressed.len() >= 2 && BigEndian::read_u16(&decompressed[0..2]) > 256
+}
+
+/// Top-left corner and size of the image an SPI renders to. Sprites keep
+/// their offset from (0, 0) so frames line up; backgrounds are cropped to
+/// the chunks they have.
+fn spi_canvas(decompressed: &[u8]) -> Option<(u32, u32, u32, u32)> {
+    let (right, bottom) = get_spi_size(decompressed);
+    let (left, top) = if is_background(decompressed) {
+        spi_chunks(decompressed)
+            .filter(|chunk| chunk.width > 0 && chunk.height > 0)
+            .fold((u32::MAX, u32::MAX), |(l, t), chunk| (l.min(chunk.x), t.min(chunk.y)))
+    } else {
+        (0, 0)
+    };
 
-        cur = &cur[8+offset..];
-        i += 1;
+    if left >= right || top >= bottom {
+        return None;
     }
+    Some((left, top, right - left, bottom - top))
 }
 
-pub fn write_spi1_png(args: &Args, decompressed: &[u8], spi: &Spi, palette: &Palette, index: u32) -> Result<()> {
-    // TODO backgrounds?
-    let offset_x = BigEndian::read_u16(&decompressed[0..2]);
-    if offset_x > 256 {
-        println!("Skip {}", offset_x);
-        return Ok(());
+/// Where the top-left corner of the image `render_spi` and
+/// `render_spi_indexed` return sits relative to the SPI's origin: (0, 0)
+/// for sprites, the corner of the chunks for backgrounds.
+pub fn spi_origin(decompressed: &[u8]) -> (u32, u32) {
+    spi_canvas(decompressed).map_or((0, 0), |(left, top, _, _)| (left, top))
+}
+
+/// Like `render_spi`, but keeps the palette indices the game stores instead
+/// of looking up colors. Pixels no chunk covers are set to `background`.
+pub fn render_spi_indexed(decompressed: &[u8], background: u8) -> Option<GrayImage> {
+    let (left, top, width, height) = spi_canvas(decompressed)?;
+
+    let mut img = GrayImage::from_pixel(width, height, Luma([background]));
+    for_each_spi_pixel(decompressed, |x, y, by| img.put_pixel(x - left, y - top, Luma([by])));
+    Some(img)
+}
+
+/// Renders a decompressed SPI, sprite or background. Returns None for empty
+/// images.
+pub fn render_spi(decompressed: &[u8], palette: &Palette) -> Option<RgbaImage> {
+    let (left, top, width, height) = spi_canvas(decompressed)?;
+
+    let mut img = RgbaImage::new(width, height);
+    for_each_spi_pixel(decompressed, |x, y, by| img.put_pixel(x - left, y - top, palette.colors[by as usize]));
+    Some(img)
+}
+
+/// Writes an 8-bit paletted PNG whose pixels are the palette indices in
+/// `img`, with PLTE and tRNS chunks built from `palette`.
+pub fn write_indexed_png(path: &Path, img: &GrayImage, palette: &Palette) -> Result<()> {
+    let plte: Vec<u8> = palette.colors.iter().flat_map(|c| [c.0[0], c.0[1], c.0[2]]).collect();
+    let mut trns: Vec<u8> = palette.colors.iter().map(|c| c.0[3]).collect();
+    // Entries past the end of tRNS are opaque.
+    while trns.last() == Some(&255) {
+        trns.pop();
     }
 
-    let (total_width, total_height) = get_spi_size(spi, decompressed);
-    let mut img = RgbaImage::new(total_width as u32, total_height as u32);
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(plte);
+    if !trns.is_empty() {
+        encoder.set_trns(trns);
+    }
 
-    write_spi_partial(&mut img, &spi, decompressed, &palette, 0, 0);
-    img.save(args.outpath.join(format!("spi1/spi1_pal{:0>2}_{:0>8}.png", palette.index, index)))?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(img.as_raw())?;
+    writer.finish()?;
     Ok(())
 }
 
-pub fn write_anim_png(args: &Args, def: &ObjDef, index: usize, spis: &[Spi], palette: &Palette) -> Result<()> {
-    let mut total_width = 0;
-    let mut total_height = 0;
-    let mut spi_data = HashMap::new();
-    let mut ey = 0;
-
-    for frame in def.frames.iter() {
-        if (frame.spi_idx & 0x8000) != 0 {
-            continue;
+/// Saves a decompressed SPI as a PNG at `path`, indexed or RGBA. Returns
+/// false without writing anything if it has no pixels.
+pub fn save_spi_png(path: &Path, decompressed: &[u8], palette: &Palette, indexed: bool) -> Result<bool> {
+    if indexed {
+        if let Some(img) = render_spi_indexed(decompressed, palette.background_index()) {
+            write_indexed_png(path, &img, palette)?;
+            return Ok(true);
         }
+    } else if let Some(img) = render_spi(decompressed, palette) {
+        img.save(path)?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+fn write_spi_png(outdir: &Path, kind: &str, decompressed: &[u8], palette: &Palette, index: u32, indexed: bool) -> Result<()> {
+    let path = outdir.join(format!("{}/{}_pal{:0>2}_{:0>8}.png", kind, kind, palette.index, index));
+    save_spi_png(&path, decompressed, palette, indexed)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ChunkEntry {
+    index: usize,
+    offset: usize,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    file: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ChunkManifest<'a> {
+    spi: u32,
+    kind: &'a str,
+    palette: usize,
+    width: u32,
+    height: u32,
+    chunks: Vec<ChunkEntry>,
+}
+
+/// Writes every chunk of a decompressed SPI as its own image into
+/// `chunks/<kind>_<index>/`, with a manifest.json listing each chunk's
+/// rectangle and its offset in the decompressed stream. Empty chunks are
+/// listed without a file.
+pub fn write_spi_chunks(outdir: &Path, kind: &str, decompressed: &[u8], palette: &Palette, index: u32, indexed: bool) -> Result<()> {
+    let dir = outdir.join(format!("chunks/{}_{:0>8}", kind, index));
+    fs::create_dir_all(&dir)?;
+
+    let (width, height) = get_spi_size(decompressed);
+    let mut entries = Vec::new();
+    for (i, chunk) in spi_chunks(decompressed).enumerate() {
+        let file = if chunk.width > 0 && chunk.height > 0 {
+            let name = format!("chunk_pal{:0>2}_{:0>3}.png", palette.index, i);
+            let img = GrayImage::from_raw(chunk.width, chunk.height, chunk.pixels.to_vec()).unwrap();
+            if indexed {
+                write_indexed_png(&dir.join(&name), &img, palette)?;
+            } else {
+                let rgba = RgbaImage::from_fn(chunk.width, chunk.height, |x, y| palette.colors[img.get_pixel(x, y).0[0] as usize]);
+                rgba.save(dir.join(&name))?;
+            }
+            Some(name)
+        } else {
+            None
+        };
+        entries.push(ChunkEntry { index: i, offset: chunk.offset, x: chunk.x, y: chunk.y, width: chunk.width, height: chunk.height, file });
+    }
+
+    let manifest = ChunkManifest { spi: index, kind, palette: palette.index, width, height, chunks: entries };
+    let path = dir.join("manifest.json");
+    fs::write(&path, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// Writes `spi0/spi0_palNN_<index>.png` under `outdir`, as a paletted PNG
+/// if `indexed` is set.
+pub fn write_spi0_png(outdir: &Path, decompressed: &[u8], palette: &Palette, index: u32, indexed: bool) -> Result<()> {
+    write_spi_png(outdir, "spi0", decompressed, palette, index, indexed)
+}
+
+pub fn write_spi1_png(outdir: &Path, decompressed: &[u8], palette: &Palette, index: u32, indexed: bool) -> Result<()> {
+    write_spi_png(outdir, "spi1", decompressed, palette, index, indexed)
+}
 
-        let spi = &spis[frame.spi_idx as usize];
-        let decomp = crate::convert::decompress_spi1(spi)?;
+pub fn write_anim_png(outdir: &Path, layout: &AnimLayout, index: usize, palette: &Palette) -> Result<()> {
+    save_anim_strip(&outdir.join(format!("anim/anim_pal{:0>2}_{:0>8}.png", palette.index, index)), layout, palette)?;
+    Ok(())
+}
 
-        let (w, h) = get_spi_size(spi, &decomp);
-        total_width += w + frame.x.abs() as u32;
-        total_height = std::cmp::max(total_height, h + frame.y as u32);
-        ey = std::cmp::max(ey, frame.y);
+/// Draws every frame of an animation side by side, each in a cell the size
+/// of the layout's canvas so the frames line up.
+fn render_anim_strip(layout: &AnimLayout, palette: &Palette) -> Option<RgbaImage> {
+    if layout.frames.is_empty() || layout.width == 0 || layout.height == 0 {
+        return None;
+    }
 
-        spi_data.insert(frame.spi_idx, decomp);
+    let mut img = RgbaImage::new(layout.width * layout.frames.len() as u32, layout.height);
+    for (cell, frame) in layout.frames.iter().enumerate() {
+        let x = cell as u32 * layout.width + frame.x;
+        write_spi_partial(&mut img, &layout.decompressed[&frame.spi_idx], palette, x, frame.y);
     }
+    Some(img)
+}
 
-    let mut img = RgbaImage::new(total_width, total_height + 100);
-    let mut x = 0;
+/// Saves an animation's frames side by side as a PNG at `path`. Returns
+/// false without writing anything if it has nothing to draw.
+pub fn save_anim_strip(path: &Path, layout: &AnimLayout, palette: &Palette) -> Result<bool> {
+    match render_anim_strip(layout, palette) {
+        Some(img) => {
+            img.save(path)?;
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
 
-    for frame in def.frames.iter() {
+// Frame delays count vertical retraces.
+pub const TICKS_PER_SECOND: u32 = 60;
+
+/// Where a frame of an animation sits on a canvas big enough for all of them.
+pub struct PlacedFrame {
+    pub index: usize,
+    pub spi_idx: u16,
+    pub x: u32,
+    pub y: u32,
+    pub delay: u8,
+}
+
+pub struct AnimLayout {
+    /// Where the canvas's top-left corner sits in the ObjDef's own frame
+    /// coordinates, so layouts of several ObjDefs can be lined up.
+    pub origin: (i32, i32),
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<PlacedFrame>,
+    pub decompressed: HashMap<u16, Arc<Vec<u8>>>,
+}
+
+/// Positions every drawable frame of an animation by its x/y offset, and
+/// fetches each SPI it uses from `cache`.
+pub fn layout_anim(def: &ObjDef, spis: &Spis, cache: &SpiCache) -> Result<AnimLayout> {
+    let mut decompressed = HashMap::new();
+    let mut placed = Vec::new();
+
+    for (index, frame) in def.frames.iter().enumerate() {
         if (frame.spi_idx & 0x8000) != 0 {
             continue;
         }
 
-        let spi = &spis[frame.spi_idx as usize];
-        let decomp = &spi_data[&frame.spi_idx];
-        let (w, h) = get_spi_size(spi, &decomp);
+        if let Entry::Vacant(entry) = decompressed.entry(frame.spi_idx) {
+            let decomp = cache.get(spis, frame.spi_idx as usize)
+                .with_context(|| format!("Frame references SPI {}", frame.spi_idx))?;
+            entry.insert(decomp);
+        }
+
+        let (w, h) = get_spi_size(&decompressed[&frame.spi_idx]);
+        placed.push((index, frame, frame.x as i32, -(frame.y as i32), w as i32, h as i32));
+    }
 
-        let px = x;
-        // println!("th={} y={} h={}", total_height, frame.y, h);
-        let py = ey as i32 - (frame.y as i32);
-        // println!("{},{}", px, py);
+    let left = placed.iter().map(|p| p.2).min().unwrap_or(0);
+    let top = placed.iter().map(|p| p.3).min().unwrap_or(0);
+    let right = placed.iter().map(|p| p.2 + p.4).max().unwrap_or(0);
+    let bottom = placed.iter().map(|p| p.3 + p.5).max().unwrap_or(0);
+
+    let frames = placed.iter()
+        .map(|(index, frame, x, y, _, _)| PlacedFrame {
+            index: *index,
+            spi_idx: frame.spi_idx,
+            x: (x - left) as u32,
+            y: (y - top) as u32,
+            delay: std::cmp::max(frame.delay, 1),
+        })
+        .collect();
+
+    Ok(AnimLayout { origin: (left, top), width: (right - left) as u32, height: (bottom - top) as u32, frames, decompressed })
+}
 
-        write_spi_partial(&mut img, spi, &decomp, &palette, px as u32, py as u32);
-        x += w as i32;
+struct AnimFrame {
+    image: RgbaImage,
+    delay: u8,
+}
+
+/// Renders every drawable frame of an animation onto canvases of the same
+/// size.
+fn render_anim_frames(layout: &AnimLayout, palette: &Palette) -> Vec<AnimFrame> {
+    let mut frames = Vec::new();
+    for frame in layout.frames.iter() {
+        let mut image = RgbaImage::new(layout.width, layout.height);
+        write_spi_partial(&mut image, &layout.decompressed[&frame.spi_idx], palette, frame.x, frame.y);
+        frames.push(AnimFrame { image, delay: frame.delay });
     }
 
-    img.save(args.outpath.join(format!("anim/anim_pal{:0>2}_{:0>8}.png", palette.index, index)))?;
+    frames
+}
+
+pub fn write_anim_gif(outdir: &Path, layout: &AnimLayout, index: usize, palette: &Palette, looping: bool) -> Result<()> {
+    save_anim_gif(&outdir.join(format!("anim/anim_pal{:0>2}_{:0>8}.gif", palette.index, index)), layout, palette, looping)?;
     Ok(())
 }
+
+/// Saves an animation as a GIF at `path`. Returns false without writing
+/// anything if it has nothing to draw.
+pub fn save_anim_gif(path: &Path, layout: &AnimLayout, palette: &Palette, looping: bool) -> Result<bool> {
+    let frames = render_anim_frames(layout, palette);
+    if frames.is_empty() || frames[0].image.width() == 0 || frames[0].image.height() == 0 {
+        return Ok(false);
+    }
+
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(path)?));
+    // Without a repeat extension the GIF plays once.
+    if looping {
+        encoder.set_repeat(Repeat::Infinite)?;
+    }
+
+    for frame in frames.into_iter() {
+        let delay = Delay::from_numer_denom_ms(frame.delay as u32 * 1000, TICKS_PER_SECOND);
+        encoder.encode_frame(image::Frame::from_parts(frame.image, 0, 0, delay))?;
+    }
+    Ok(true)
+}
+
+pub fn write_anim_apng(outdir: &Path, layout: &AnimLayout, index: usize, palette: &Palette, looping: bool) -> Result<()> {
+    save_anim_apng(&outdir.join(format!("anim/anim_pal{:0>2}_{:0>8}.apng", palette.index, index)), layout, palette, looping)?;
+    Ok(())
+}
+
+/// Saves an animation as an APNG at `path`. Returns false without writing
+/// anything if it has nothing to draw.
+pub fn save_anim_apng(path: &Path, layout: &AnimLayout, palette: &Palette, looping: bool) -> Result<bool> {
+    let frames = render_anim_frames(layout, palette);
+    if frames.is_empty() || frames[0].image.width() == 0 || frames[0].image.height() == 0 {
+        return Ok(false);
+    }
+
+    let (width, height) = frames[0].image.dimensions();
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    // A play count of 0 loops forever.
+    encoder.set_animated(frames.len() as u32, if looping { 0 } else { 1 })?;
+
+    let mut writer = encoder.write_header()?;
+    for frame in frames.iter() {
+        writer.set_frame_delay(frame.delay as u16, TICKS_PER_SECOND as u16)?;
+        writer.write_image_data(frame.image.as_raw())?;
+    }
+    writer.finish()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use image::Rgba;
+
+    fn chunk(x: u16, y: u16, w: u16, h: u16, index: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        for v in [x, y, w, h] {
+            out.write_u16::<BigEndian>(v).unwrap();
+        }
+        out.extend(std::iter::repeat_n(index, w as usize * h as usize));
+        out
+    }
+
+    #[test]
+    fn background_origin_matches_crop() {
+        let decomp = [chunk(300, 500, 4, 2, 1), chunk(304, 498, 2, 2, 2)].concat();
+        assert!(is_background(&decomp));
+        assert_eq!(spi_origin(&decomp), (300, 498));
+
+        let img = render_spi_indexed(&decomp, 0).unwrap();
+        assert_eq!(img.dimensions(), (6, 4));
+        assert_eq!(img.get_pixel(0, 2).0, [1]);
+        assert_eq!(img.get_pixel(4, 0).0, [2]);
+    }
+
+    #[test]
+    fn sprite_origin_is_zero() {
+        let decomp = chunk(3, 4, 2, 2, 1);
+        assert_eq!(spi_origin(&decomp), (0, 0));
+        assert_eq!(render_spi_indexed(&decomp, 0).unwrap().dimensions(), (5, 6));
+    }
+
+    #[test]
+    fn strip_has_a_cell_per_frame() {
+        let decomp = Arc::new(chunk(0, 0, 2, 3, 1));
+        let layout = AnimLayout {
+            origin: (-1, -4),
+            width: 3,
+            height: 5,
+            frames: vec![
+                PlacedFrame { index: 0, spi_idx: 0, x: 0, y: 2, delay: 1 },
+                PlacedFrame { index: 1, spi_idx: 0, x: 1, y: 0, delay: 1 },
+            ],
+            decompressed: HashMap::from([(0, decomp)]),
+        };
+        let palette = Palette { index: 0, colors: vec![Rgba([0, 0, 0, 0]), Rgba([255, 0, 0, 255])] };
+
+        let img = render_anim_strip(&layout, &palette).unwrap();
+        assert_eq!(img.dimensions(), (6, 5));
+        assert_eq!(img.get_pixel(0, 2).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(0, 1).0[3], 0);
+        assert_eq!(img.get_pixel(4, 0).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(3, 0).0[3], 0);
+    }
+
+    #[test]
+    fn wrong_magic_is_an_error() {
+        let spi0 = crate::compress::compress_spi0(&[1; 0x40]);
+        let spi1 = crate::compress::compress_spi1(&[1; 0x40]);
+        assert!(decompress_spi1(&spi0).is_err());
+        assert!(decompress_spi0(&spi1).is_err());
+        assert_eq!(decompress_spi0(&spi0).unwrap(), [1; 0x40]);
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::obj::{Frame, ObjDef, ObjInfo, Tables};
+use crate::profile::{Profile, OBJINFO_SIZE, OBJDEF_SIZE, FRAME_SIZE};
+
+#[derive(Serialize)]
+pub struct ObjInfoRecord {
+    pub index: usize,
+    pub rom_offset: usize,
+    pub offset1: u16,
+    pub offset2: u16,
+    pub u1: u32,
+    pub flags: Vec<&'static str>,
+    pub u2: u16,
+    pub u3: u16,
+    pub obj_count: u8,
+    pub extra_obj_count: u8,
+}
+
+/// A frame as dumped. `index` and `rom_offset` are informational and may be
+/// left out when importing; the frame's position in its list is what counts.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FrameRecord {
+    #[serde(default)]
+    pub index: usize,
+    #[serde(default)]
+    pub rom_offset: usize,
+    pub spi_idx: u16,
+    pub kind: u8,
+    pub id: u8,
+    pub delay: u8,
+    pub u2: u8,
+    pub x: i16,
+    pub y: i16,
+    pub u5: u16,
+    pub u6: u8,
+    pub u7: u8,
+}
+
+/// An ObjDef as dumped. On import `index` picks the ObjDef to overwrite,
+/// while `rom_offset` and `frames_offset` are ignored; frame lists are
+/// placed by `rebuild::import_defs`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ObjDefRecord {
+    pub index: usize,
+    #[serde(default)]
+    pub rom_offset: usize,
+    #[serde(default)]
+    pub frames_offset: u32,
+    pub u1: u16,
+    pub u2: u16,
+    pub u3: u16,
+    pub u4: u16,
+    pub u5: u32,
+    pub frames: Vec<FrameRecord>,
+}
+
+#[derive(Serialize)]
+pub struct TableDump {
+    pub profile: String,
+    pub objinfos: Vec<ObjInfoRecord>,
+    pub objdefs: Vec<ObjDefRecord>,
+}
+
+fn objinfo_record(profile: &Profile, index: usize, obj: &ObjInfo) -> ObjInfoRecord {
+    ObjInfoRecord {
+        index,
+        rom_offset: profile.objinfo_offset + index * OBJINFO_SIZE,
+        offset1: obj.offset1,
+        offset2: obj.offset2,
+        u1: obj.u1,
+        flags: obj.flags.names(),
+        u2: obj.u2,
+        u3: obj.u3,
+        obj_count: obj.obj_count,
+        extra_obj_count: obj.extra_obj_count,
+    }
+}
+
+fn frame_record(profile: &Profile, def: &ObjDef, index: usize, frame: &Frame) -> FrameRecord {
+    FrameRecord {
+        index,
+        rom_offset: profile.frames_base_offset + def.frames_offset as usize + index * FRAME_SIZE,
+        spi_idx: frame.spi_idx,
+        kind: frame.kind,
+        id: frame.id,
+        delay: frame.delay,
+        u2: frame.u2,
+        x: frame.x,
+        y: frame.y,
+        u5: frame.u5,
+        u6: frame.u6,
+        u7: frame.u7,
+    }
+}
+
+fn objdef_record(profile: &Profile, index: usize, def: &ObjDef) -> ObjDefRecord {
+    ObjDefRecord {
+        index,
+        rom_offset: profile.defs_offset + index * OBJDEF_SIZE,
+        frames_offset: def.frames_offset,
+        u1: def.u1,
+        u2: def.u2,
+        u3: def.u3,
+        u4: def.u4,
+        u5: def.u5,
+        frames: def.frames.iter().enumerate().map(|(j, frame)| frame_record(profile, def, j, frame)).collect(),
+    }
+}
+
+/// Every ObjInfo, and every ObjDef with its frames, tagged with its index
+/// and where it was read from in the ROM.
+pub fn dump_tables(profile: &Profile, tables: &Tables) -> TableDump {
+    TableDump {
+        profile: profile.name.clone(),
+        objinfos: tables.objinfos.iter().enumerate().map(|(i, obj)| objinfo_record(profile, i, obj)).collect(),
+        objdefs: tables.defs.iter().enumerate().map(|(i, def)| objdef_record(profile, i, def)).collect(),
+    }
+}
+
+/// The part of a dump that can be written back. Other sections, such as
+/// `objinfos`, are ignored, so a whole edited dump can be imported as is.
+#[derive(Deserialize)]
+pub struct TableImport {
+    pub objdefs: Vec<ObjDefRecord>,
+}
+
+/// Reads a dump, as YAML if it has a .yaml/.yml extension and as JSON
+/// otherwise.
+pub fn load_import(path: &Path) -> Result<TableImport> {
+    let text = fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let is_yaml = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+    let import = if is_yaml {
+        serde_yaml::from_str(&text).with_context(|| format!("Unable to parse {}", path.display()))?
+    } else {
+        serde_json::from_str(&text).with_context(|| format!("Unable to parse {}", path.display()))?
+    };
+    Ok(import)
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use anyhow::{Context, Result};
+use josette::{aseprite, convert, obj, palette};
+use josette::cache::SpiCache;
+use josette::obj::{ObjInfoFlags, Palette, Spis, Tables};
+use josette::palette::PaletteFormat;
+use josette::profile::{Profile, OBJDEF_SIZE};
+use crate::{AnimFormat, ExtractArgs, PaletteSelection};
+
+/// Runs `job` for every index below `count` on `jobs` threads. Results are
+/// taken in index order, so the debug text each job returns and the
+/// progress line read the same as a serial run, and the error reported is
+/// always the first one by index.
+fn run_jobs<F>(label: &str, count: usize, jobs: usize, job: F) -> Result<()>
+where
+    F: Fn(usize) -> Result<String> + Sync,
+{
+    run_jobs_to(&mut io::stdout(), label, count, jobs, job)
+}
+
+/// `run_jobs`, writing the text jobs return to `out`.
+fn run_jobs_to<W, F>(out: &mut W, label: &str, count: usize, jobs: usize, job: F) -> Result<()>
+where
+    W: Write,
+    F: Fn(usize) -> Result<String> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..jobs.clamp(1, count.max(1)) {
+            let tx = tx.clone();
+            let (next, failed, job) = (&next, &failed, &job);
+            scope.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= count {
+                        break;
+                    }
+                    let result = job(i);
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut pending = BTreeMap::new();
+        let mut done = 0;
+        for (i, result) in rx {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&done) {
+                write!(out, "{}", result.with_context(|| format!("Unable to extract {} {}", label, done))?)?;
+                done += 1;
+                if done % 64 == 0 || done == count {
+                    eprint!("\r{} {}/{}", label, done, count);
+                    io::stderr().flush()?;
+                }
+            }
+        }
+        if count > 0 {
+            eprintln!();
+        }
+        Ok(())
+    })
+}
+
+/// Writes every palette, SPI and animation in the ROM to the output
+/// directory as the command line asks.
+pub fn extract(args: &ExtractArgs, profile: &Profile, buffer: &[u8]) -> Result<()> {
+    let tables = obj::parse_tables(profile, buffer)?;
+    if args.debug {
+        for (i, def) in tables.defs.iter().enumerate() {
+            println!("frames offset {:02x} {} ind {:02x}", def.frames_offset, i, profile.defs_offset + i * OBJDEF_SIZE);
+        }
+    }
+    let (force, bank) = match &args.palette {
+        None => (None, None),
+        Some(PaletteSelection::All) => (None, Some((0..tables.palettes.len()).collect::<Vec<_>>())),
+        Some(PaletteSelection::List(list)) if list.len() == 1 => (Some(list[0]), None),
+        Some(PaletteSelection::List(list)) => (None, Some(list.clone())),
+    };
+    let palette_map = crate::palette_map(&tables, args.palette_source, force)?;
+    let Tables { objinfos, defs, palettes, .. } = &tables;
+    let spis = Spis::new(profile, buffer);
+    // The SPI pass fills the cache, so animations only draw.
+    let cache = match &args.cache_dir {
+        Some(dir) => SpiCache::with_dir(dir)?,
+        None => SpiCache::new(),
+    };
+
+    // With several palettes selected every sprite is written once per
+    // palette into palNN/, otherwise once in its own palette.
+    let mut bank_targets = Vec::new();
+    for &p in bank.iter().flatten() {
+        bank_targets.push((args.outpath.join(format!("pal{:0>2}", p)), tables.palette(p)?));
+    }
+    let dirs = match bank {
+        Some(_) => bank_targets.iter().map(|(dir, _)| dir.clone()).collect(),
+        None => vec![args.outpath.to_path_buf()],
+    };
+    for dir in dirs.iter() {
+        for kind in ["spi0", "spi1", "anim"] {
+            fs::create_dir_all(dir.join(kind))?;
+        }
+    }
+    let targets = |own: usize| -> Vec<(PathBuf, &Palette)> {
+        match bank {
+            Some(_) => bank_targets.clone(),
+            None => vec![(args.outpath.to_path_buf(), &palettes[own])],
+        }
+    };
+
+    let palette_formats: Vec<PaletteFormat> = if args.palette_format.is_empty() {
+        vec![PaletteFormat::Png]
+    } else {
+        args.palette_format.iter().map(|&f| f.into()).collect()
+    };
+    for (i, pal) in palettes.iter().enumerate() {
+        for format in palette_formats.iter() {
+            let path = args.outpath.join(format!("palette/palette_{:02}.{}", i, format.extension()));
+            palette::write_palette(&path, pal, *format)?;
+        }
+    }
+
+    run_jobs("spi", spis.len(), args.jobs(), |i| {
+        let mut log = String::new();
+        let spi = spis.get(i)?;
+        if args.debug {
+            writeln!(log, "spi {}: {} {:04x} at {:#x}", i, spi.header.magic, spi.header.u1, spis.offset(i)?)?;
+        }
+
+        if spi.header.magic == "SPI0" && !args.no_spi0 {
+            let decomp = cache.get(&spis, i)?;
+            for (dir, palette) in targets(palette_map.spis[i]) {
+                convert::write_spi0_png(&dir, &decomp, palette, i as u32, args.indexed)?;
+                if args.chunks {
+                    convert::write_spi_chunks(&dir, "spi0", &decomp, palette, i as u32, args.indexed)?;
+                }
+            }
+        }
+
+        if spi.header.magic == "SPI1" && !args.no_spi1 {
+            let decomp = cache.get(&spis, i)?;
+            for (dir, palette) in targets(palette_map.spis[i]) {
+                convert::write_spi1_png(&dir, &decomp, palette, i as u32, args.indexed)?;
+                if args.chunks {
+                    convert::write_spi_chunks(&dir, "spi1", &decomp, palette, i as u32, args.indexed)?;
+                }
+            }
+        }
+        Ok(log)
+    })?;
+
+    for (i, obj) in objinfos.iter().enumerate() {
+        if args.debug {
+            println!("def {}: {:04x} {:04x} {:08x} objs={} extra={} flags={:?}", i, obj.offset1, obj.offset2, obj.u1, obj.obj_count, obj.extra_obj_count, obj.flags);
+        }
+    }
+
+    let flags = obj::def_flags(objinfos, defs.len());
+    let owners = obj::def_objinfos(objinfos, defs.len());
+
+    run_jobs("anim", defs.len(), args.jobs(), |i| {
+        let mut log = String::new();
+        let def = &defs[i];
+        if args.debug {
+            writeln!(log, "OBJ {}: {:08x}, {}", i, def.frames_offset, def.frame_count)?;
+
+            for frame in def.frames.iter() {
+                writeln!(log, "\t{:0>8} {:08x} {:08x} {} {} {} {} {} {} {}", frame.spi_idx, frame.kind, frame.id, frame.x, frame.y, frame.delay, frame.u2, frame.u5, frame.u6, frame.u7)?;
+            }
+        }
+
+        // ObjDefs an ObjInfo lists go into that ObjInfo's Aseprite file below.
+        if args.anim_format == AnimFormat::Aseprite && !owners[i].is_empty() {
+            return Ok(log);
+        }
+
+        let layout = convert::layout_anim(def, &spis, &cache)?;
+        let looping = flags[i].contains(ObjInfoFlags::LOOP);
+        for (dir, palette) in targets(palette_map.defs[i]) {
+            match args.anim_format {
+                AnimFormat::Strip => convert::write_anim_png(&dir, &layout, i, palette)?,
+                AnimFormat::Gif => convert::write_anim_gif(&dir, &layout, i, palette, looping)?,
+                AnimFormat::Apng => convert::write_anim_apng(&dir, &layout, i, palette, looping)?,
+                AnimFormat::Aseprite => {
+                    let path = dir.join(format!("anim/anim_pal{:0>2}_{:0>8}.aseprite", palette.index, i));
+                    aseprite::write_anim_aseprite(&path, &[(i, &layout)], palette, looping)?
+                },
+            }
+        }
+        Ok(log)
+    })?;
+
+    if args.anim_format == AnimFormat::Aseprite {
+        // One file per ObjInfo, drawn in the palette of its first ObjDef.
+        run_jobs("objinfo", objinfos.len(), args.jobs(), |o| {
+            let obj = &objinfos[o];
+            let indices: Vec<usize> = obj.def_ranges().into_iter().flatten().filter(|&i| i < defs.len()).collect();
+            let first = match indices.first() {
+                Some(&first) => first,
+                None => return Ok(String::new()),
+            };
+            let layouts = indices.iter()
+                .map(|&i| convert::layout_anim(&defs[i], &spis, &cache))
+                .collect::<Result<Vec<_>>>()?;
+            let anims: Vec<_> = indices.iter().copied().zip(layouts.iter()).collect();
+            let looping = obj.flags.contains(ObjInfoFlags::LOOP);
+            for (dir, palette) in targets(palette_map.defs[first]) {
+                let path = dir.join(format!("anim/objinfo_pal{:0>2}_{:0>4}.aseprite", palette.index, o));
+                aseprite::write_anim_aseprite(&path, &anims, palette, looping)?;
+            }
+            Ok(String::new())
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_report_in_index_order() {
+        let job = |i: usize| {
+            // Later indices finish first.
+            thread::sleep(Duration::from_millis(5 * (8 - i) as u64));
+            match i {
+                3 | 5 => Err(anyhow!("job {} failed", i)),
+                _ => Ok(format!("{}\n", i)),
+            }
+        };
+
+        // Every job runs at once, so 5 fails before 3 does.
+        let mut out = Vec::new();
+        let err = run_jobs_to(&mut out, "test", 8, 8, job).unwrap_err();
+        assert_eq!(String::from_utf8(out).unwrap(), "0\n1\n2\n");
+        assert_eq!(err.to_string(), "Unable to extract test 3");
+        assert_eq!(err.root_cause().to_string(), "job 3 failed");
+
+        let mut out = Vec::new();
+        run_jobs_to(&mut out, "test", 8, 3, |i| job(i).or_else(|_| Ok(format!("{}\n", i)))).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "0\n1\n2\n3\n4\n5\n6\n7\n");
+    }
+}
@@ -0,0 +1,32 @@
+//! Reading and rebuilding the sprite data of Wonder Project J2.
+//!
+//! Open a ROM with [`Rom::open`] and use its tables, SPIs and palettes
+//! directly; [`convert`] decodes and renders SPIs and animations without
+//! touching the file system unless asked to.
+
+#[macro_use] extern crate anyhow;
+extern crate nom;
+extern crate hexyl;
+extern crate tribool;
+extern crate rgb;
+extern crate image;
+extern crate byteorder;
+#[macro_use] extern crate bitflags;
+
+pub mod aseprite;
+pub mod atlas;
+pub mod cache;
+pub mod compress;
+pub mod convert;
+pub mod dump;
+pub mod n64;
+pub mod obj;
+pub mod palette;
+pub mod profile;
+pub mod rebuild;
+pub mod rom;
+pub mod scan;
+pub mod spi;
+pub mod writeable;
+
+pub use rom::Rom;
@@ -2,59 +2,677 @@
 #![allow(dead_code)]
 
 #[macro_use] extern crate anyhow;
-extern crate nom;
-extern crate hexyl;
-extern crate tribool;
-extern crate rgb;
-extern crate image;
-extern crate byteorder;
-#[macro_use] extern crate bitflags;
 extern crate clap;
 
-mod convert;
-mod obj;
-mod spi;
-mod writeable;
+mod extract;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{ArgEnum, Args, Parser, Subcommand};
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use writeable::*;
+use std::thread;
+use josette::{aseprite, atlas, convert, dump, n64, obj, profile, rebuild, scan};
+use josette::cache::SpiCache;
+use josette::obj::ObjInfoFlags;
+use josette::rom::{identify, read_rom, Rom};
+use josette::obj::{PaletteMap, PaletteSource, Tables};
+use josette::palette::PaletteFormat;
+use josette::writeable::Writeable;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-pub struct Args {
-    /// Path to Wonder Project J2 ROM (.z64)
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Args, Debug)]
+pub struct ExtractArgs {
+    /// Path to Wonder Project J2 ROM (.z64, .v64 or .n64)
     rompath: PathBuf,
 
-    /// Output path
+    /// Directory to write palettes, SPIs and animations to
     outpath: PathBuf,
 
-    /// Palette to use when exporting
-    #[clap(short, long, default_value_t = 0)]
-    palette: usize,
+    /// TOML or JSON file overriding table offsets and counts
+    #[clap(long)]
+    profile: Option<PathBuf>,
+
+    /// Draw every sprite with this palette instead of its own. Several
+    /// palettes, e.g. "0-3,8" or "all", write every sprite once per palette
+    /// into palNN/ directories
+    #[clap(short, long, parse(try_from_str = parse_palette_selection))]
+    palette: Option<PaletteSelection>,
+
+    /// Experimental: read each sprite's palette from this field. None is
+    /// confirmed, so without it every sprite uses palette 0
+    #[clap(long, arg_enum)]
+    palette_source: Option<PaletteSourceArg>,
+
+    /// Write SPIs as paletted PNGs that keep the original color indices
+    #[clap(long)]
+    indexed: bool,
+
+    /// Formats to write palettes in, comma-separated [default: png]
+    #[clap(long, arg_enum, use_value_delimiter = true)]
+    palette_format: Vec<PaletteFormatArg>,
+
+    /// Also write each chunk of an SPI as its own image, with a manifest of
+    /// the chunk rectangles, under chunks/
+    #[clap(long)]
+    chunks: bool,
+
+    /// Format to write animations in
+    #[clap(long, arg_enum, default_value_t = AnimFormat::Strip)]
+    anim_format: AnimFormat,
+
+    /// Keep decompressed SPIs in this directory to speed up later runs
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Number of threads to extract with [default: one per CPU]
+    #[clap(short, long)]
+    jobs: Option<usize>,
 
     /// Print debugging information
     #[clap(short, long)]
     debug: bool,
 
+    /// Don't output SPI0 data
+    #[clap(long)]
+    no_spi0: bool,
+
     /// Don't output SPI1 data
     #[clap(short, long)]
     no_spi1: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+/// Command-line names for `obj::PaletteSource`.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteSourceArg {
+    /// `u2` of the first ObjInfo listing the ObjDef
+    ObjinfoU2,
+    /// `u3` of the first ObjInfo listing the ObjDef
+    ObjinfoU3,
+    /// The ObjDef's own `u1`
+    ObjdefU1,
+    /// The ObjDef's own `u2`
+    ObjdefU2,
+    /// The ObjDef's own `u3`
+    ObjdefU3,
+}
+
+impl From<PaletteSourceArg> for PaletteSource {
+    fn from(arg: PaletteSourceArg) -> Self {
+        match arg {
+            PaletteSourceArg::ObjinfoU2 => PaletteSource::ObjinfoU2,
+            PaletteSourceArg::ObjinfoU3 => PaletteSource::ObjinfoU3,
+            PaletteSourceArg::ObjdefU1 => PaletteSource::ObjdefU1,
+            PaletteSourceArg::ObjdefU2 => PaletteSource::ObjdefU2,
+            PaletteSourceArg::ObjdefU3 => PaletteSource::ObjdefU3,
+        }
+    }
+}
+
+/// Command-line names for `palette::PaletteFormat`.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteFormatArg {
+    /// 256x1 PNG strip
+    Png,
+    /// JASC-PAL (.pal) with an alpha column
+    Jasc,
+    /// GIMP palette (.gpl) with an alpha column
+    Gpl,
+    /// Adobe Color Table (.act) with a transparent index
+    Act,
+    /// The ROM's big-endian RGBA5551 bytes (.bin)
+    Raw,
+}
+
+impl From<PaletteFormatArg> for PaletteFormat {
+    fn from(arg: PaletteFormatArg) -> Self {
+        match arg {
+            PaletteFormatArg::Png => PaletteFormat::Png,
+            PaletteFormatArg::Jasc => PaletteFormat::Jasc,
+            PaletteFormatArg::Gpl => PaletteFormat::Gpl,
+            PaletteFormatArg::Act => PaletteFormat::Act,
+            PaletteFormatArg::Raw => PaletteFormat::Raw,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnimFormat {
+    /// Every frame side by side in one PNG
+    Strip,
+    /// Animated GIF
+    Gif,
+    /// Animated PNG
+    Apng,
+    /// Indexed-color Aseprite file per ObjInfo, with a tag per ObjDef
+    Aseprite,
+}
+
+impl AnimFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AnimFormat::Strip => "png",
+            AnimFormat::Gif => "gif",
+            AnimFormat::Apng => "apng",
+            AnimFormat::Aseprite => "aseprite",
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Json,
+    Yaml,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListKind {
+    /// Every SPI with its magic, ROM offset and sizes
+    Spis,
+    /// Every ObjDef with its frame count and flags
+    Anims,
+    /// Every palette with its ROM offset and transparent index
+    Palettes,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderKind {
+    /// A single SPI
+    Spi,
+    /// An ObjDef's animation
+    Anim,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the ROM header and a summary of its tables
+    Info {
+        /// Path to Wonder Project J2 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// TOML or JSON file overriding table offsets and counts
+        #[clap(long)]
+        profile: Option<PathBuf>,
+    },
+
+    /// List the SPIs, animations or palettes in the ROM
+    List {
+        /// Path to Wonder Project J2 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// What to list
+        #[clap(arg_enum)]
+        kind: ListKind,
+
+        /// TOML or JSON file overriding table offsets and counts
+        #[clap(long)]
+        profile: Option<PathBuf>,
+    },
+
+    /// Write every palette, SPI and animation in the ROM
+    Extract(ExtractArgs),
+
+    /// Write a new ROM with SPI containers replaced
+    Rebuild {
+        /// Path to Wonder Project J2 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// Path to write the rebuilt ROM to, in the input's byte order
+        outpath: PathBuf,
+
+        /// Replacement as INDEX=PATH, either an SPI container or a
+        /// decompressed stream to compress
+        #[clap(short, long = "replace", required_unless_present = "tables", parse(try_from_str = parse_replacement))]
+        replacements: Vec<(usize, PathBuf)>,
+
+        /// Edited `dump` output (JSON or YAML) whose ObjDefs and frames to
+        /// write back
+        #[clap(short, long)]
+        tables: Option<PathBuf>,
+
+        /// TOML or JSON file overriding table offsets and counts
+        #[clap(long)]
+        profile: Option<PathBuf>,
+    },
+
+    /// Check the ROM header checksum, optionally repairing it
+    Crc {
+        /// Path to N64 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// Recompute the checksum and write it back
+        #[clap(short, long)]
+        fix: bool,
+
+        /// Write the fixed ROM here instead of overwriting the input
+        #[clap(short, long, requires = "fix")]
+        output: Option<PathBuf>,
+    },
+
+    /// Search the ROM for SPI containers and their offset table, and print a
+    /// profile for them
+    Scan {
+        /// Path to N64 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// Write the profile here instead of printing it
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write the ObjInfo, ObjDef and Frame tables as JSON or YAML
+    Dump {
+        /// Path to Wonder Project J2 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// Write the dump here instead of printing it
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Output format
+        #[clap(short, long, arg_enum, default_value_t = DumpFormat::Json)]
+        format: DumpFormat,
+
+        /// TOML or JSON file overriding table offsets and counts
+        #[clap(long)]
+        profile: Option<PathBuf>,
+    },
+
+    /// Draw a single SPI or animation
+    Render {
+        /// Path to Wonder Project J2 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// What to draw
+        #[clap(arg_enum)]
+        kind: RenderKind,
+
+        /// SPI or ObjDef index
+        index: usize,
+
+        /// File to write [default: named as `extract` would, in the current
+        /// directory]
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Draw with this palette instead of the sprite's own
+        #[clap(short, long)]
+        palette: Option<usize>,
+
+        /// Experimental: read each sprite's palette from this field. None
+        /// is confirmed, so without it every sprite uses palette 0
+        #[clap(long, arg_enum)]
+        palette_source: Option<PaletteSourceArg>,
+
+        /// Write an SPI as a paletted PNG that keeps the original color
+        /// indices
+        #[clap(long)]
+        indexed: bool,
+
+        /// Format to write an animation in
+        #[clap(long, arg_enum, default_value_t = AnimFormat::Strip)]
+        anim_format: AnimFormat,
+
+        /// TOML or JSON file overriding table offsets and counts
+        #[clap(long)]
+        profile: Option<PathBuf>,
+    },
+
+    /// Pack sprites into atlas PNGs with TexturePacker-style JSON
+    Atlas {
+        /// Path to Wonder Project J2 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// Directory to write atlases to
+        outpath: PathBuf,
+
+        /// ObjDefs whose frames to pack, e.g. "0-10,15"; every SPI is packed
+        /// when left out
+        #[clap(long)]
+        anims: Option<String>,
+
+        /// Draw every sprite with this palette instead of its own
+        #[clap(short, long)]
+        palette: Option<usize>,
+
+        /// Experimental: read each sprite's palette from this field. None
+        /// is confirmed, so without it every sprite uses palette 0
+        #[clap(long, arg_enum)]
+        palette_source: Option<PaletteSourceArg>,
 
-    let mut f = File::open(&args.rompath).context("Unable to open file").unwrap();
+        /// Largest width and height of an atlas page
+        #[clap(long, default_value_t = 2048)]
+        max_size: u32,
+
+        /// TOML or JSON file overriding table offsets and counts
+        #[clap(long)]
+        profile: Option<PathBuf>,
+    },
+
+    /// Convert a .v64 or .n64 ROM to big-endian .z64
+    ConvertRom {
+        /// Path to N64 ROM (.z64, .v64 or .n64)
+        rompath: PathBuf,
+
+        /// Path to write the .z64 ROM to
+        outpath: PathBuf,
+    },
+}
+
+impl ExtractArgs {
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+/// `Tables::palette_map`, warning when `source` left ObjDefs without a
+/// usable palette.
+pub fn palette_map(tables: &Tables, source: Option<PaletteSourceArg>, force: Option<usize>) -> Result<PaletteMap> {
+    let map = tables.palette_map(source.map(PaletteSource::from), force)?;
+    if let (Some(source), true) = (source, map.fallbacks > 0) {
+        let name = source.to_possible_value().map_or_else(|| format!("{:?}", source), |v| v.get_name().to_string());
+        eprintln!("warning: {} of {} ObjDefs have no palette in {}; drawing them with palette {}",
+                  map.fallbacks, tables.defs.len(), name, obj::DEFAULT_PALETTE);
+    }
+    Ok(map)
+}
+
+fn parse_replacement(s: &str) -> Result<(usize, PathBuf)> {
+    let (index, path) = s.split_once('=').ok_or_else(|| anyhow!("expected INDEX=PATH, got {:?}", s))?;
+    Ok((index.parse()?, PathBuf::from(path)))
+}
+
+/// Parses a list of indices and inclusive ranges such as "0-10,15".
+fn parse_index_list(s: &str) -> Result<Vec<usize>> {
+    let mut indices = Vec::new();
+    for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end): (usize, usize) = (start.trim().parse()?, end.trim().parse()?);
+                if end < start {
+                    return Err(anyhow!("Range {:?} is backwards", part));
+                }
+                indices.extend(start..=end);
+            },
+            None => indices.push(part.parse()?),
+        }
+    }
+    Ok(indices)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaletteSelection {
+    All,
+    List(Vec<usize>),
+}
+
+fn parse_palette_selection(s: &str) -> Result<PaletteSelection> {
+    if s.trim().eq_ignore_ascii_case("all") {
+        return Ok(PaletteSelection::All);
+    }
+    let list = parse_index_list(s)?;
+    if list.is_empty() {
+        return Err(anyhow!("No palettes given"));
+    }
+    Ok(PaletteSelection::List(list))
+}
+
+fn rebuild(rompath: &Path, outpath: &Path, replacements: &[(usize, PathBuf)], tables: Option<&Path>, profile_path: Option<&Path>) -> Result<()> {
+    let (buffer, format) = read_rom(rompath)?;
+    let profile = identify(&buffer, profile_path)?;
+    profile.validate(&buffer)?;
+
+    let mut spis = Vec::new();
+    for (index, path) in replacements.iter() {
+        spis.push((*index, rebuild::load_replacement(&profile, &buffer, *index, path)?));
+    }
+
+    let defs = match tables {
+        Some(path) => dump::load_import(path)?.objdefs,
+        None => Vec::new(),
+    };
+
+    let mut rom = rebuild::rebuild(&profile, &buffer, &spis, &defs)?;
+    // Written in the byte order it was read in, like `crc --fix`.
+    n64::convert_format(&mut rom, format);
+    fs::write(outpath, rom).with_context(|| format!("Unable to write {}", outpath.display()))
+}
+
+fn crc(rompath: &Path, fix: bool, output: Option<&Path>) -> Result<()> {
+    let (mut buffer, format) = read_rom(rompath)?;
+
+    let status = n64::verify_crc(&buffer)?;
+    println!("CIC:  {}", status.cic);
+    println!("CRC1: {:08x} (calculated {:08x})", status.stored.0, status.calculated.0);
+    println!("CRC2: {:08x} (calculated {:08x})", status.stored.1, status.calculated.1);
+
+    if status.is_valid() {
+        println!("Checksum OK");
+    } else {
+        println!("Checksum mismatch");
+    }
+
+    if fix {
+        n64::fix_crc(&mut buffer)?;
+        // Written back in the byte order it was read in.
+        n64::convert_format(&mut buffer, format);
+        let outpath = output.unwrap_or(rompath);
+        fs::write(outpath, buffer).with_context(|| format!("Unable to write {}", outpath.display()))?;
+        println!("Wrote {}", outpath.display());
+    }
+
+    Ok(())
+}
+
+fn atlas(rompath: &Path, outpath: &Path, anims: Option<&str>, palette: Option<usize>, palette_source: Option<PaletteSourceArg>, max_size: u32, profile_path: Option<&Path>) -> Result<()> {
+    let (buffer, _) = read_rom(rompath)?;
+    let profile = identify(&buffer, profile_path)?;
+    let tables = obj::parse_tables(&profile, &buffer)?;
+    let spis = obj::Spis::new(&profile, &buffer);
+    let cache = SpiCache::new();
+    let palette = palette_map(&tables, palette_source, palette)?;
+
+    let (sprites, tags, prefix) = match anims {
+        Some(anims) => {
+            let (sprites, tags) = atlas::anim_sprites(&tables, &spis, &cache, &parse_index_list(anims)?, &palette)?;
+            (sprites, tags, "anim_atlas")
+        },
+        None => (atlas::spi_sprites(&tables, &spis, &cache, &palette)?, Vec::new(), "spi_atlas"),
+    };
+
+    fs::create_dir_all(outpath)?;
+    let pages = atlas::write_atlases(outpath, prefix, &sprites, &tags, max_size)?;
+    println!("Packed {} sprites into {} atlas(es)", sprites.len(), pages);
+    Ok(())
+}
+
+fn convert_rom(rompath: &Path, outpath: &Path) -> Result<()> {
+    let mut f = File::open(rompath).context("Unable to open file")?;
     let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer).context("Unable to read file").unwrap();
+    f.read_to_end(&mut buffer).context("Unable to read file")?;
+
+    let format = n64::normalize(&mut buffer)?;
+    println!("Converting from {}", format);
+    fs::write(outpath, buffer).with_context(|| format!("Unable to write {}", outpath.display()))
+}
+
+fn scan(rompath: &Path, output: Option<&Path>) -> Result<()> {
+    let (buffer, _) = read_rom(rompath)?;
+    let header = n64::header(&buffer)?;
+
+    let hits = scan::find_spis(&buffer);
+    let spi0 = hits.iter().filter(|hit| hit.spi.header.magic == "SPI0").count();
+    println!("Found {} SPI containers ({} SPI0, {} SPI1)", hits.len(), spi0, hits.len() - spi0);
+
+    let table = scan::find_spi_table(&buffer, &hits)
+        .ok_or_else(|| anyhow!("Unable to locate the SPI offset table"))?;
+    println!("SPI offset table at {:#x}, {} entries relative to {:#x}", table.offset_offset, table.count, table.base_offset);
+
+    let (base, note) = match profile::identify(&header) {
+        Ok(profile) => (profile, "taken from the profile for this ROM"),
+        Err(_) => (profile::Profile::wonder_project_j2(), "copied from Wonder Project J2 (Japan) and likely wrong"),
+    };
+    let mut profile = scan::apply_table(base, &table);
+    profile.name = format!("{} ({})", header.title, header.game_code);
+
+    let text = format!("# Generated by `josette scan`. Only the spi_* fields were located;\n# the rest are {}.\n{}",
+                       note, profile.to_toml());
+    match output {
+        Some(path) => fs::write(path, text).with_context(|| format!("Unable to write {}", path.display()))?,
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+fn dump(rompath: &Path, output: Option<&Path>, format: DumpFormat, profile_path: Option<&Path>) -> Result<()> {
+    let (buffer, _) = read_rom(rompath)?;
+    let profile = identify(&buffer, profile_path)?;
+    let tables = obj::parse_tables(&profile, &buffer)?;
+
+    let dump = dump::dump_tables(&profile, &tables);
+    let text = match format {
+        DumpFormat::Json => serde_json::to_string_pretty(&dump)? + "\n",
+        DumpFormat::Yaml => serde_yaml::to_string(&dump)?,
+    };
+    match output {
+        Some(path) => fs::write(path, text).with_context(|| format!("Unable to write {}", path.display()))?,
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+fn info(rompath: &Path, profile_path: Option<&Path>) -> Result<()> {
+    let rom = Rom::open_with_profile(rompath, profile_path)?;
+    let header = n64::header(rom.data())?;
+    let checksum = match n64::verify_crc(rom.data()) {
+        Ok(status) if status.is_valid() => format!("OK ({})", status.cic),
+        Ok(status) => format!("mismatch ({})", status.cic),
+        Err(e) => format!("unknown ({})", e),
+    };
+
+    let mut spi0 = 0;
+    for spi in rom.spis().iter() {
+        if spi?.header.magic == "SPI0" {
+            spi0 += 1;
+        }
+    }
+    let frames: usize = rom.objdefs().iter().map(|def| def.frames.len()).sum();
+
+    println!("Title:     {}", header.title);
+    println!("Game code: {} (revision {})", header.game_code, header.revision);
+    println!("Format:    {}", rom.format());
+    println!("Checksum:  {}", checksum);
+    println!("Profile:   {}", rom.profile().name);
+    println!("ObjInfos:  {}", rom.objinfos().len());
+    println!("ObjDefs:   {} ({} frames)", rom.objdefs().len(), frames);
+    println!("SPIs:      {} ({} SPI0, {} SPI1)", rom.spi_count(), spi0, rom.spi_count() - spi0);
+    println!("Palettes:  {}", rom.palettes().len());
+
+    Ok(())
+}
+
+fn list(rompath: &Path, kind: ListKind, profile_path: Option<&Path>) -> Result<()> {
+    let rom = Rom::open_with_profile(rompath, profile_path)?;
+
+    match kind {
+        ListKind::Spis => {
+            let spis = rom.spis();
+            println!("index  magic  offset     size     decompressed");
+            for (i, spi) in spis.iter().enumerate() {
+                let spi = spi?;
+                println!("{:5}  {}   {:#09x}  {:7}  {:7}", i, spi.header.magic, spis.offset(i)?, spi.byte_size(), spi.header.u1);
+            }
+        },
+        ListKind::Anims => {
+            let flags = obj::def_flags(rom.objinfos(), rom.objdefs().len());
+            println!("index  frames  frames_offset  flags");
+            for (i, def) in rom.objdefs().iter().enumerate() {
+                println!("{:5}  {:6}  {:#010x}     {}", i, def.frames.len(), def.frames_offset, flags[i].names().join(","));
+            }
+        },
+        ListKind::Palettes => {
+            println!("index  offset     transparent");
+            for palette in rom.palettes().iter() {
+                let transparent = palette.transparent_index().map_or_else(|| "none".to_string(), |i| i.to_string());
+                println!("{:5}  {:#09x}  {:>11}", palette.index, rom.profile().palette_offset(palette.index), transparent);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render(rompath: &Path, kind: RenderKind, index: usize, output: Option<&Path>, palette: Option<usize>, palette_source: Option<PaletteSourceArg>, indexed: bool, anim_format: AnimFormat, profile_path: Option<&Path>) -> Result<()> {
+    let rom = Rom::open_with_profile(rompath, profile_path)?;
+    let palette_map = palette_map(rom.tables(), palette_source, palette)?;
+
+    let path = match kind {
+        RenderKind::Spi => {
+            let magic = rom.spi(index)?.header.magic.to_lowercase();
+            let palette = rom.palette(palette_map.spis[index])?;
+            let path = output.map_or_else(|| PathBuf::from(format!("{}_pal{:0>2}_{:0>8}.png", magic, palette.index, index)), Path::to_path_buf);
+            if !convert::save_spi_png(&path, &rom.decompress_spi(index)?, palette, indexed)? {
+                return Err(anyhow!("SPI {} has no pixels", index));
+            }
+            path
+        },
+        RenderKind::Anim => {
+            let layout = rom.layout_anim(index)?;
+            if layout.frames.is_empty() {
+                return Err(anyhow!("ObjDef {} has no frames to draw", index));
+            }
+            let palette = rom.palette(palette_map.defs[index])?;
+            let path = output.map_or_else(|| PathBuf::from(format!("anim_pal{:0>2}_{:0>8}.{}", palette.index, index, anim_format.extension())), Path::to_path_buf);
+
+            let looping = obj::def_flags(rom.objinfos(), rom.objdefs().len())[index].contains(ObjInfoFlags::LOOP);
+            match anim_format {
+                AnimFormat::Strip => { convert::save_anim_strip(&path, &layout, palette)?; },
+                AnimFormat::Gif => { convert::save_anim_gif(&path, &layout, palette, looping)?; },
+                AnimFormat::Apng => { convert::save_anim_apng(&path, &layout, palette, looping)?; },
+                AnimFormat::Aseprite => aseprite::write_anim_aseprite(&path, &[(index, &layout)], palette, looping)?,
+            }
+            path
+        },
+    };
+
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match &cli.command {
+        Command::Info { rompath, profile } => info(rompath, profile.as_deref())?,
+        Command::List { rompath, kind, profile } => list(rompath, *kind, profile.as_deref())?,
+        Command::Extract(args) => {
+            let (buffer, _) = read_rom(&args.rompath)?;
+            let profile = identify(&buffer, args.profile.as_deref())?;
+
+            fs::create_dir_all(args.outpath.join("palette"))?;
 
-    fs::create_dir_all(args.outpath.join("palette")).unwrap();
-    fs::create_dir_all(args.outpath.join("spi1")).unwrap();
-    fs::create_dir_all(args.outpath.join("anim")).unwrap();
+            extract::extract(args, &profile, &buffer)?
+        },
+        Command::Render { rompath, kind, index, output, palette, palette_source, indexed, anim_format, profile } =>
+            render(rompath, *kind, *index, output.as_deref(), *palette, *palette_source, *indexed, *anim_format, profile.as_deref())?,
+        Command::Rebuild { rompath, outpath, replacements, tables, profile } =>
+            rebuild(rompath, outpath, replacements, tables.as_deref(), profile.as_deref())?,
+        Command::Crc { rompath, fix, output } => crc(rompath, *fix, output.as_deref())?,
+        Command::Scan { rompath, output } => scan(rompath, output.as_deref())?,
+        Command::Dump { rompath, output, format, profile } => dump(rompath, output.as_deref(), *format, profile.as_deref())?,
+        Command::Atlas { rompath, outpath, anims, palette, palette_source, max_size, profile } =>
+            atlas(rompath, outpath, anims.as_deref(), *palette, *palette_source, *max_size, profile.as_deref())?,
+        Command::ConvertRom { rompath, outpath } => convert_rom(rompath, outpath)?,
+    }
 
-    obj::parse_objinfos(&args, &buffer);
+    Ok(())
 }
@@ -0,0 +1,297 @@
+use std::fmt;
+use anyhow::Result;
+use byteorder::{ByteOrder, BigEndian};
+
+pub const CRC1_OFFSET: usize = 0x10;
+pub const CRC2_OFFSET: usize = 0x14;
+
+const TITLE_OFFSET: usize = 0x20;
+const TITLE_LENGTH: usize = 20;
+const GAME_CODE_OFFSET: usize = 0x3B;
+const REVISION_OFFSET: usize = 0x3F;
+
+const BOOT_CODE_START: usize = 0x40;
+const BOOT_CODE_END: usize = 0x1000;
+const CHECKSUM_START: usize = 0x1000;
+const CHECKSUM_LENGTH: usize = 0x100000;
+
+/// Byte order of a ROM dump, named after the usual file extensions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RomFormat {
+    /// Big-endian, as the cartridge is laid out.
+    Z64,
+    /// Every 16-bit halfword byte-swapped.
+    V64,
+    /// Every 32-bit word little-endian.
+    N64,
+}
+
+impl fmt::Display for RomFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RomFormat::Z64 => ".z64 (big-endian)",
+            RomFormat::V64 => ".v64 (byte-swapped)",
+            RomFormat::N64 => ".n64 (little-endian)",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Detects the byte order from the first header word, which is 0x80371240
+/// on every retail cartridge.
+pub fn detect_format(rom: &[u8]) -> Result<RomFormat> {
+    if rom.len() < 4 || !rom.len().is_multiple_of(4) {
+        return Err(anyhow!("ROM size {:#x} is not a whole number of words", rom.len()));
+    }
+
+    match rom[0..4] {
+        [0x80, 0x37, 0x12, 0x40] => Ok(RomFormat::Z64),
+        [0x37, 0x80, 0x40, 0x12] => Ok(RomFormat::V64),
+        [0x40, 0x12, 0x37, 0x80] => Ok(RomFormat::N64),
+        _ => Err(anyhow!("Unrecognized ROM header word {:02x?}", &rom[0..4]))
+    }
+}
+
+/// Converts a ROM in any supported byte order to .z64 layout in place.
+/// Returns the format it was in.
+pub fn normalize(rom: &mut [u8]) -> Result<RomFormat> {
+    let format = detect_format(rom)?;
+    convert_format(rom, format);
+    Ok(format)
+}
+
+/// Converts a .z64 ROM to `format` in place, undoing `normalize`. Both
+/// swaps are their own inverse, so this also converts `format` to .z64.
+pub fn convert_format(rom: &mut [u8], format: RomFormat) {
+    match format {
+        RomFormat::Z64 => (),
+        RomFormat::V64 => {
+            for half in rom.chunks_exact_mut(2) {
+                half.swap(0, 1);
+            }
+        },
+        RomFormat::N64 => {
+            for word in rom.chunks_exact_mut(4) {
+                word.reverse();
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RomHeader {
+    pub title: String,
+    /// Media type, cartridge ID and region, e.g. "NJ2J".
+    pub game_code: String,
+    pub revision: u8,
+}
+
+pub fn header(rom: &[u8]) -> Result<RomHeader> {
+    if rom.len() < BOOT_CODE_START {
+        return Err(anyhow!("ROM is too small to have a header ({:#x} bytes)", rom.len()));
+    }
+
+    let title = String::from_utf8_lossy(&rom[TITLE_OFFSET..TITLE_OFFSET+TITLE_LENGTH])
+        .trim_end_matches([' ', '\0'])
+        .to_string();
+    let game_code = String::from_utf8_lossy(&rom[GAME_CODE_OFFSET..GAME_CODE_OFFSET+4]).to_string();
+    let revision = rom[REVISION_OFFSET];
+    Ok(RomHeader { title, game_code, revision })
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Cic {
+    Cic6101,
+    Cic6102,
+    Cic6103,
+    Cic6105,
+    Cic6106,
+}
+
+impl Cic {
+    fn seed(&self) -> u32 {
+        match self {
+            Cic::Cic6101 | Cic::Cic6102 => 0xF8CA4DDC,
+            Cic::Cic6103 => 0xA3886759,
+            Cic::Cic6105 => 0xDF26F436,
+            Cic::Cic6106 => 0x1FEA617A,
+        }
+    }
+}
+
+impl fmt::Display for Cic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cic::Cic6101 => "CIC-NUS-6101",
+            Cic::Cic6102 => "CIC-NUS-6102",
+            Cic::Cic6103 => "CIC-NUS-6103",
+            Cic::Cic6105 => "CIC-NUS-6105",
+            Cic::Cic6106 => "CIC-NUS-6106",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CrcStatus {
+    pub cic: Cic,
+    pub stored: (u32, u32),
+    pub calculated: (u32, u32),
+}
+
+impl CrcStatus {
+    pub fn is_valid(&self) -> bool {
+        self.stored == self.calculated
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn check_size(rom: &[u8]) -> Result<()> {
+    if rom.len() < CHECKSUM_START + CHECKSUM_LENGTH {
+        return Err(anyhow!("ROM is too small to checksum ({:#x} bytes)", rom.len()));
+    }
+    Ok(())
+}
+
+/// Identifies the boot chip the ROM expects from a CRC32 of its boot code.
+pub fn detect_cic(rom: &[u8]) -> Result<Cic> {
+    check_size(rom)?;
+
+    match crc32(&rom[BOOT_CODE_START..BOOT_CODE_END]) {
+        0x6170A4A1 => Ok(Cic::Cic6101),
+        0x90BB6CB5 => Ok(Cic::Cic6102),
+        0x0B050EE0 => Ok(Cic::Cic6103),
+        0x98BC2C86 => Ok(Cic::Cic6105),
+        0xACC8580A => Ok(Cic::Cic6106),
+        crc => Err(anyhow!("Unknown boot code (CRC32 {:08x})", crc))
+    }
+}
+
+/// Computes the CRC1/CRC2 header words the boot code checks against.
+pub fn calculate_crc(rom: &[u8], cic: Cic) -> Result<(u32, u32)> {
+    check_size(rom)?;
+
+    let seed = cic.seed();
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+
+    for i in (CHECKSUM_START..CHECKSUM_START + CHECKSUM_LENGTH).step_by(4) {
+        let d = BigEndian::read_u32(&rom[i..i+4]);
+        let (sum, carry) = t6.overflowing_add(d);
+        if carry {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = sum;
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+
+        if cic == Cic::Cic6105 {
+            let ind = 0x750 + (i & 0xFF);
+            t1 = t1.wrapping_add(BigEndian::read_u32(&rom[ind..ind+4]) ^ d);
+        } else {
+            t1 = t1.wrapping_add(t5 ^ d);
+        }
+    }
+
+    Ok(match cic {
+        Cic::Cic6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        Cic::Cic6106 => (t6.wrapping_mul(t4).wrapping_add(t3), t5.wrapping_mul(t2).wrapping_add(t1)),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    })
+}
+
+pub fn verify_crc(rom: &[u8]) -> Result<CrcStatus> {
+    let cic = detect_cic(rom)?;
+    let calculated = calculate_crc(rom, cic)?;
+    let stored = (
+        BigEndian::read_u32(&rom[CRC1_OFFSET..CRC1_OFFSET+4]),
+        BigEndian::read_u32(&rom[CRC2_OFFSET..CRC2_OFFSET+4]),
+    );
+    Ok(CrcStatus { cic, stored, calculated })
+}
+
+/// Recomputes and writes the CRC1/CRC2 header words. Returns the status from
+/// before the fix.
+pub fn fix_crc(rom: &mut [u8]) -> Result<CrcStatus> {
+    let status = verify_crc(rom)?;
+    BigEndian::write_u32(&mut rom[CRC1_OFFSET..CRC1_OFFSET+4], status.calculated.0);
+    BigEndian::write_u32(&mut rom[CRC2_OFFSET..CRC2_OFFSET+4], status.calculated.1);
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A checksum-sized ROM filled from xorshift32, so every word differs.
+    fn test_rom() -> Vec<u8> {
+        let mut x = 1u32;
+        let mut rom = Vec::with_capacity(CHECKSUM_START + CHECKSUM_LENGTH);
+        while rom.len() < CHECKSUM_START + CHECKSUM_LENGTH {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            rom.extend_from_slice(&x.to_be_bytes());
+        }
+        rom
+    }
+
+    // Expected values come from the reference n64crc.c run over the same data.
+    #[test]
+    fn crc_matches_reference_for_each_cic() {
+        let rom = test_rom();
+        let expected = [
+            (Cic::Cic6101, (0x4126D6C9, 0x1BB5D2FE)),
+            (Cic::Cic6102, (0x4126D6C9, 0x1BB5D2FE)),
+            (Cic::Cic6103, (0x2FE70050, 0xF49349AD)),
+            (Cic::Cic6105, (0x6B8D0F33, 0x962C49FA)),
+            (Cic::Cic6106, (0x73DDBDBA, 0x8686D88A)),
+        ];
+        for (cic, crc) in expected {
+            assert_eq!(calculate_crc(&rom, cic).unwrap(), crc, "{}", cic);
+        }
+    }
+
+    #[test]
+    fn short_rom_is_an_error() {
+        assert!(calculate_crc(&[0; CHECKSUM_START], Cic::Cic6102).is_err());
+    }
+
+    #[test]
+    fn normalize_round_trips() {
+        let mut z64 = vec![0x80, 0x37, 0x12, 0x40];
+        z64.extend(0..0x3cu8);
+
+        for (format, first_word) in [
+            (RomFormat::V64, [0x37, 0x80, 0x40, 0x12]),
+            (RomFormat::N64, [0x40, 0x12, 0x37, 0x80]),
+        ] {
+            let mut rom = z64.clone();
+            convert_format(&mut rom, format);
+            assert_eq!(rom[0..4], first_word);
+            assert_eq!(detect_format(&rom).unwrap(), format);
+
+            assert_eq!(normalize(&mut rom).unwrap(), format);
+            assert_eq!(rom, z64);
+        }
+
+        let mut rom = z64.clone();
+        assert_eq!(normalize(&mut rom).unwrap(), RomFormat::Z64);
+        assert_eq!(rom, z64);
+    }
+}
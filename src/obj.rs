@@ -1,11 +1,9 @@
-use nom::{ToUsize, IResult};
-use nom::number::streaming::{be_u8, be_u32};
-use nom::bytes::streaming::*;
-use nom::multi::count;
-use anyhow::{Context, Result};
-use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
-use crate::Args;
-use image::{Rgba, RgbaImage};
+use std::ops::Range;
+use anyhow::Result;
+use byteorder::{ByteOrder, BigEndian};
+use crate::profile::{Profile, OBJINFO_SIZE, OBJDEF_SIZE, FRAME_SIZE, SPI_OFFSET_SIZE, PALETTE_SIZE};
+use image::Rgba;
+use crate::spi::Spi;
 
 bitflags! {
     #[repr(C)]
@@ -30,6 +28,20 @@ bitflags! {
     }
 }
 
+impl ObjInfoFlags {
+    const NAMES: [(&'static str, ObjInfoFlags); 16] = [
+        ("HASEXTRA", Self::HASEXTRA), ("LOOP", Self::LOOP), ("UNK3", Self::UNK3), ("UNK4", Self::UNK4),
+        ("BG2FG", Self::BG2FG), ("UNK6", Self::UNK6), ("UNK8", Self::UNK8), ("FG2BG", Self::FG2BG),
+        ("UNK10", Self::UNK10), ("UNK12", Self::UNK12), ("UNK13", Self::UNK13), ("UNK14", Self::UNK14),
+        ("UNK15", Self::UNK15), ("UNK16", Self::UNK16), ("UNK17", Self::UNK17), ("UNK18", Self::UNK18),
+    ];
+
+    /// Names of the set flags, lowest bit first.
+    pub fn names(&self) -> Vec<&'static str> {
+        Self::NAMES.iter().filter(|(_, flag)| self.contains(*flag)).map(|(name, _)| *name).collect()
+    }
+}
+
 pub struct ObjInfo {
     pub offset1: u16,
     pub offset2: u16,
@@ -41,6 +53,45 @@ pub struct ObjInfo {
     pub extra_obj_count: u8,
 }
 
+impl ObjInfo {
+    /// The ranges of ObjDef indices this ObjInfo lists: `obj_count` from
+    /// `offset1`, and with `HASEXTRA` another `extra_obj_count` from
+    /// `offset2`. This is read off the field layout, which pairs each
+    /// offset with a count, rather than confirmed against the game code;
+    /// without the flag `offset2` is ignored.
+    pub fn def_ranges(&self) -> Vec<Range<usize>> {
+        let main = self.offset1 as usize..self.offset1 as usize + self.obj_count as usize;
+        if self.flags.contains(ObjInfoFlags::HASEXTRA) {
+            vec![main, self.offset2 as usize..self.offset2 as usize + self.extra_obj_count as usize]
+        } else {
+            vec![main]
+        }
+    }
+}
+
+/// Which ObjInfos list each ObjDef, through either of its `def_ranges`.
+pub fn def_objinfos(objinfos: &[ObjInfo], def_count: usize) -> Vec<Vec<usize>> {
+    let mut owners = vec![Vec::new(); def_count];
+    for (i, obj) in objinfos.iter().enumerate() {
+        for range in obj.def_ranges() {
+            let end = std::cmp::min(range.end, def_count);
+            for def_owners in owners.iter_mut().take(end).skip(range.start) {
+                if def_owners.last() != Some(&i) {
+                    def_owners.push(i);
+                }
+            }
+        }
+    }
+    owners
+}
+
+/// Flags for each ObjDef, taken from the ObjInfos that list it.
+pub fn def_flags(objinfos: &[ObjInfo], def_count: usize) -> Vec<ObjInfoFlags> {
+    def_objinfos(objinfos, def_count).iter()
+        .map(|owners| owners.iter().fold(ObjInfoFlags::EMPTY, |flags, &i| flags | objinfos[i].flags))
+        .collect()
+}
+
 pub struct ObjDef {
     pub frames_offset: u32,
     pub u1: u16,
@@ -68,46 +119,187 @@ pub struct Frame {
     pub u7: u8,
 }
 
-fn get_slice<'a>(slice: &'a [u8], offset: u32, size: u32) -> &'a [u8] {
-    let offset = offset as usize;
-    let size = size as usize;
+pub struct Palette {
+    pub index: usize,
+    pub colors: Vec<Rgba<u8>>
+}
+
+impl Palette {
+    /// The first color with its alpha bit clear, for formats that only allow
+    /// one transparent index. None if every color is opaque.
+    pub fn transparent_index(&self) -> Option<u8> {
+        self.colors.iter().position(|c| c.0[3] == 0).map(|i| i as u8)
+    }
 
-    &slice[offset..(offset+size)]
+    /// The index to fill pixels no chunk covers with: the transparent
+    /// color, or 0 when the palette has none.
+    pub fn background_index(&self) -> u8 {
+        self.transparent_index().unwrap_or(0)
+    }
 }
 
-unsafe fn transmute_slice<'a, T>(slice: &'a [u8], offset: u32, size: u32) -> &'a [T] {
-    let t_slice = get_slice(slice, offset, size);
-    std::slice::from_raw_parts(t_slice.as_ptr() as *const _, size as usize / std::mem::size_of::<T>())
+/// Looks up the offset table entry for an SPI, relative to
+/// `spi_base_offset`. Entries with the low bit set are skipped in favor of
+/// the next one. Returns (entry index, offset).
+pub fn spi_offset(profile: &Profile, buffer: &[u8], i: usize) -> Result<(usize, u32)> {
+    let mut off = 0;
+    loop {
+        let ind = profile.spi_offset_offset + (i + off) * SPI_OFFSET_SIZE;
+        if ind + SPI_OFFSET_SIZE > buffer.len() {
+            return Err(anyhow!("SPI offset table entry {} is past the end of the ROM", i + off));
+        }
+        let spi_offset = BigEndian::read_u32(&buffer[ind..ind+4]);
+        if spi_offset & 1 == 0 {
+            return Ok((i + off, spi_offset))
+        }
+        off += 1;
+    }
 }
 
-pub struct Palette {
-    pub index: usize,
-    pub colors: Vec<Rgba<u8>>
+/// The SPI containers in a ROM. Nothing is parsed up front; `get` reads
+/// a single container in place, borrowing its payload from the ROM.
+#[derive(Clone, Copy)]
+pub struct Spis<'a> {
+    profile: &'a Profile,
+    buffer: &'a [u8],
 }
 
-pub fn parse_objinfos(args: &Args, buffer: &[u8]) -> Result<()>{
-    // headers
-    let offset = 0x000f27e0;
+impl<'a> Spis<'a> {
+    pub fn new(profile: &'a Profile, buffer: &'a [u8]) -> Spis<'a> {
+        Spis { profile, buffer }
+    }
+
+    pub fn len(&self) -> usize {
+        self.profile.spi_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-    // frame count header offset
-    let defs_offset = 0x000fd180;
+    /// Where SPI `i` starts in the ROM.
+    pub fn offset(&self, i: usize) -> Result<usize> {
+        if i >= self.len() {
+            return Err(anyhow!("SPI {} out of range (max {})", i, self.len() as isize - 1));
+        }
+        let (_, spi_offset) = spi_offset(self.profile, self.buffer, i)?;
+        Ok(self.profile.spi_base_offset + spi_offset as usize)
+    }
 
-    // frame data offset
-    let frames_base_offset = 0x00105220;
+    pub fn get(&self, i: usize) -> Result<Spi<'a>> {
+        let spi_begin = self.offset(i)?;
+        if spi_begin > self.buffer.len() {
+            return Err(anyhow!("SPI {} at {:#x} is past the end of the ROM", i, spi_begin));
+        }
+        let (_, spi) = crate::spi::spi(&self.buffer[spi_begin..])
+            .map_err(|e| anyhow!("Unable to parse SPI {} at {:#x}: {:?}", i, spi_begin, e))?;
+        Ok(spi)
+    }
 
-    // spi offsets offset
-    let spi_offset_offset = 0x00133ac0;
+    pub fn iter(&self) -> impl Iterator<Item = Result<Spi<'a>>> + 'a {
+        let table = *self;
+        (0..self.len()).map(move |i| table.get(i))
+    }
+}
 
-    // spi data offset
-    let spi_base_offset = 0x0013d2e0;
+pub struct Tables {
+    pub objinfos: Vec<ObjInfo>,
+    pub defs: Vec<ObjDef>,
+    pub spi_count: usize,
+    pub palettes: Vec<Palette>,
+}
+
+/// A field that might hold a sprite's palette index. None of these is
+/// confirmed to be the one the game uses, so nothing reads them unless asked
+/// to; `PaletteMap` counts the ObjDefs a source gave no usable palette,
+/// which is how a wrong choice shows up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteSource {
+    /// `u2` of the first ObjInfo listing the ObjDef
+    ObjinfoU2,
+    /// `u3` of the first ObjInfo listing the ObjDef
+    ObjinfoU3,
+    /// The ObjDef's own `u1`
+    ObjdefU1,
+    /// The ObjDef's own `u2`
+    ObjdefU2,
+    /// The ObjDef's own `u3`
+    ObjdefU3,
+}
+
+/// Palette used for sprites with no palette source, or whose source field
+/// doesn't name a palette.
+pub const DEFAULT_PALETTE: usize = 0;
+
+/// The palette index each ObjDef and SPI is drawn with.
+pub struct PaletteMap {
+    pub defs: Vec<usize>,
+    pub spis: Vec<usize>,
+    /// ObjDefs whose source field was missing or out of range, and so fell
+    /// back to `DEFAULT_PALETTE`.
+    pub fallbacks: usize,
+}
+
+impl Tables {
+    pub fn palette(&self, i: usize) -> Result<&Palette> {
+        self.palettes.get(i)
+            .ok_or_else(|| anyhow!("Palette {} out of range (max {})", i, self.palettes.len() as isize - 1))
+    }
+
+    /// Picks a palette for every ObjDef from the field named by `source`,
+    /// falling back to `DEFAULT_PALETTE` when the field is out of range.
+    /// An SPI takes the palette of the first ObjDef that draws it. `force`,
+    /// or no `source`, puts everything on one palette instead.
+    pub fn palette_map(&self, source: Option<PaletteSource>, force: Option<usize>) -> Result<PaletteMap> {
+        let source = match (source, force) {
+            (Some(source), None) => source,
+            _ => {
+                let i = force.unwrap_or(DEFAULT_PALETTE);
+                self.palette(i)?;
+                return Ok(PaletteMap { defs: vec![i; self.defs.len()], spis: vec![i; self.spi_count], fallbacks: 0 });
+            }
+        };
+
+        let owners = def_objinfos(&self.objinfos, self.defs.len());
+        let defs: Vec<Option<usize>> = self.defs.iter().enumerate()
+            .map(|(i, def)| {
+                let objinfo = owners[i].first().map(|&o| &self.objinfos[o]);
+                let value = match source {
+                    PaletteSource::ObjinfoU2 => objinfo.map(|obj| obj.u2 as usize),
+                    PaletteSource::ObjinfoU3 => objinfo.map(|obj| obj.u3 as usize),
+                    PaletteSource::ObjdefU1 => Some(def.u1 as usize),
+                    PaletteSource::ObjdefU2 => Some(def.u2 as usize),
+                    PaletteSource::ObjdefU3 => Some(def.u3 as usize),
+                };
+                value.filter(|&v| v < self.palettes.len())
+            })
+            .collect();
+        let fallbacks = defs.iter().filter(|p| p.is_none()).count();
+        let defs: Vec<usize> = defs.into_iter().map(|p| p.unwrap_or(DEFAULT_PALETTE)).collect();
+
+        let mut spis: Vec<Option<usize>> = vec![None; self.spi_count];
+        for (def, &palette) in self.defs.iter().zip(defs.iter()) {
+            for frame in def.frames.iter() {
+                if let Some(spi) = spis.get_mut(frame.spi_idx as usize) {
+                    spi.get_or_insert(palette);
+                }
+            }
+        }
+        let spis = spis.into_iter().map(|p| p.unwrap_or(DEFAULT_PALETTE)).collect();
+
+        Ok(PaletteMap { defs, spis, fallbacks })
+    }
+}
+
+pub fn parse_tables(profile: &Profile, buffer: &[u8]) -> Result<Tables> {
+    profile.validate(buffer)?;
 
     let mut objinfos = Vec::new();
     let mut defs = Vec::new();
-    let mut spis = Vec::new();
     let mut palettes = Vec::new();
 
-    for i in 0..0x9B4 {
-        let ind = offset + i * 0x10;
+    for i in 0..profile.objinfo_count {
+        let ind = profile.objinfo_offset + i * OBJINFO_SIZE;
         let offset1 = BigEndian::read_u16(&buffer[ind..ind+2]);
         let offset2 = BigEndian::read_u16(&buffer[ind+2..ind+4]);
         let u1 = BigEndian::read_u32(&buffer[ind+4..ind+8]);
@@ -119,8 +311,8 @@ pub fn parse_objinfos(args: &Args, buffer: &[u8]) -> Result<()>{
         objinfos.push(ObjInfo { offset1, offset2, u1, flags: ObjInfoFlags { bits: flags }, u2, u3, obj_count, extra_obj_count });
     }
 
-    for i in 0..1645 {
-        let ind = defs_offset + i * 0x14;
+    for i in 0..profile.defs_count {
+        let ind = profile.defs_offset + i * OBJDEF_SIZE;
         let frames_offset = BigEndian::read_u32(&buffer[ind..ind+4]);
         let u1 = BigEndian::read_u16(&buffer[ind+4..ind+6]);
         let u2 = BigEndian::read_u16(&buffer[ind+6..ind+8]);
@@ -129,14 +321,13 @@ pub fn parse_objinfos(args: &Args, buffer: &[u8]) -> Result<()>{
         let u5 = BigEndian::read_u32(&buffer[ind+12..ind+16]);
         let frame_count = buffer[ind+16];
 
-        if args.debug {
-            println!("frames offset {:02x} {} ind {:02x}", frames_offset, i, ind);
-        }
-
         let mut frames = Vec::new();
         for j in 0..frame_count {
-            let ind = frames_base_offset + (frames_offset as usize) + (j as usize) * 0xe;
-            let spi_idx = BigEndian::read_u16(&buffer[ind+0..ind+2]);
+            let ind = profile.frames_base_offset + (frames_offset as usize) + (j as usize) * FRAME_SIZE;
+            if ind + FRAME_SIZE > buffer.len() {
+                return Err(anyhow!("Frame {} of ObjDef {} is past the end of the ROM", j, i));
+            }
+            let spi_idx = BigEndian::read_u16(&buffer[ind..ind+2]);
             let kind = buffer[ind+2];
             let id = buffer[ind+3];
             let delay = buffer[ind+4];
@@ -144,37 +335,17 @@ pub fn parse_objinfos(args: &Args, buffer: &[u8]) -> Result<()>{
             let x = BigEndian::read_i16(&buffer[ind+6..ind+8]);
             let y = BigEndian::read_i16(&buffer[ind+8..ind+10]);
             let u5 = BigEndian::read_u16(&buffer[ind+10..ind+12]);
-            let u6 = buffer[12];
-            let u7 = buffer[13];
+            let u6 = buffer[ind+12];
+            let u7 = buffer[ind+13];
             frames.push(Frame { spi_idx, kind, id, delay, u2, x, y, u5, u6, u7 });
         }
 
-        defs.push(ObjDef { frames_offset, u1, u2, u3, u4, u5, frame_count, pad1: 0, pad2: 0, pad3: 0, frames: frames });
+        defs.push(ObjDef { frames_offset, u1, u2, u3, u4, u5, frame_count, pad1: 0, pad2: 0, pad3: 0, frames });
     }
 
-    for i in 0..0x1303 {
-        let mut off = 0;
-        let mut spi_offset = 0;
-        loop {
-            let ind = spi_offset_offset + (i + off) * 8;
-            spi_offset = BigEndian::read_u32(&buffer[ind..ind+8]);
-            if spi_offset & 1 == 0 {
-                break
-            }
-            off += 1;
-        }
-
-        if args.debug {
-            println!("spi offset {:02x}: {:02x} {:02x}", i, spi_offset, spi_base_offset + spi_offset as usize);
-        }
-
-        let (_, spi) = crate::spi::spi(&buffer[spi_base_offset+spi_offset as usize..]).map_err(|e| anyhow!("Parsing failed! {:?}", e))?;
-        spis.push(spi);
-    }
-
-    for i in 0..0x60 {
-        let pal_begin = 0xf27e0 + (((i * 0x200) + 0x8078D1C0) - 0x80400000);
-        let palette = &buffer[pal_begin..pal_begin+0x200];
+    for i in 0..profile.palette_count {
+        let pal_begin = profile.palette_offset(i);
+        let palette = &buffer[pal_begin..pal_begin+PALETTE_SIZE];
 
         let mut colors = Vec::new();
         for i in 0..0x100 {
@@ -190,49 +361,55 @@ pub fn parse_objinfos(args: &Args, buffer: &[u8]) -> Result<()>{
             colors.push(Rgba::<u8>([r as u8, g as u8, b as u8, a as u8]));
         }
 
-        palettes.push(Palette { index: i, colors: colors })
+        palettes.push(Palette { index: i, colors })
     }
 
-    let palette = &palettes[args.palette];
+    Ok(Tables { objinfos, defs, spi_count: profile.spi_count, palettes })
+}
 
-    for (i, pal) in palettes.iter().enumerate() {
-        let mut palimg = RgbaImage::new(256, 1);
-        for (x, col) in pal.colors.iter().enumerate() {
-            palimg.put_pixel(x as u32, 0, *col);
-        }
-        palimg.save(args.outpath.join(format!("palette/palette_{:02}.png", i)))?;
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for (i, spi) in spis.iter().enumerate() {
-        if args.debug {
-            println!("spi {}: {} {:04x}", i, spi.header.magic, spi.header.u1);
-        }
+    fn objinfo(flags: ObjInfoFlags, offset1: u16, obj_count: u8, offset2: u16, extra_obj_count: u8) -> ObjInfo {
+        ObjInfo { offset1, offset2, u1: 0, flags, u2: 0, u3: 0, obj_count, extra_obj_count }
+    }
 
-        if !args.no_spi1 {
-            if spi.header.magic == "SPI1" {
-                let decomp = crate::convert::decompress_spi1(spi)?;
-                crate::convert::write_spi1_png(&args, &decomp, spi, palette, i as u32);
-            }
-        }
+    #[test]
+    fn extra_range_needs_hasextra() {
+        let objinfos = [
+            objinfo(ObjInfoFlags::LOOP | ObjInfoFlags::HASEXTRA, 0, 2, 5, 2),
+            objinfo(ObjInfoFlags::EMPTY, 2, 1, 3, 2),
+        ];
+        let owners = def_objinfos(&objinfos, 7);
+        assert_eq!(owners, vec![vec![0], vec![0], vec![1], vec![], vec![], vec![0], vec![0]]);
+
+        let flags = def_flags(&objinfos, 7);
+        assert!(flags[6].contains(ObjInfoFlags::LOOP));
+        assert!(!flags[3].contains(ObjInfoFlags::LOOP));
     }
 
-    for (i, obj) in objinfos.iter().enumerate() {
-        if args.debug {
-            println!("def {}: {:04x} {:04x} {:08x} objs={} extra={} flags={:?}", i, obj.offset1, obj.offset2, obj.u1, obj.obj_count, obj.extra_obj_count, obj.flags);
-        }
+    fn objdef(u1: u16) -> ObjDef {
+        ObjDef { frames_offset: 0, u1, u2: 0, u3: 0, u4: 0, u5: 0, frame_count: 0, pad1: 0, pad2: 0, pad3: 0, frames: Vec::new() }
     }
 
-    for (i, def) in defs.iter().enumerate() {
-        if args.debug {
-            println!("OBJ {}: {:08x}, {}", i, def.frames_offset, def.frame_count);
+    #[test]
+    fn palette_source_is_opt_in() {
+        let tables = Tables {
+            objinfos: Vec::new(),
+            defs: vec![objdef(1), objdef(9)],
+            spi_count: 1,
+            palettes: (0..2).map(|index| Palette { index, colors: Vec::new() }).collect(),
+        };
 
-            for frame in def.frames.iter() {
-                println!("\t{:0>8} {:08x} {:08x} {} {} {} {} {} {} {}", frame.spi_idx, frame.kind, frame.id, frame.x, frame.y, frame.delay, frame.u2, frame.u5, frame.u6, frame.u7);
-            }
-        }
+        let map = tables.palette_map(None, None).unwrap();
+        assert_eq!((map.defs, map.spis, map.fallbacks), (vec![DEFAULT_PALETTE; 2], vec![DEFAULT_PALETTE], 0));
 
-        crate::convert::write_anim_png(&args, &def, i, &spis, &palette)?;
-    }
+        let map = tables.palette_map(Some(PaletteSource::ObjdefU1), None).unwrap();
+        assert_eq!((map.defs, map.fallbacks), (vec![1, DEFAULT_PALETTE], 1));
 
-    Ok(())
+        let map = tables.palette_map(Some(PaletteSource::ObjdefU1), Some(1)).unwrap();
+        assert_eq!((map.defs, map.fallbacks), (vec![1, 1], 0));
+        assert!(tables.palette_map(None, Some(2)).is_err());
+    }
 }
@@ -0,0 +1,126 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, WriteBytesExt};
+use image::RgbaImage;
+use crate::obj::Palette;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteFormat {
+    /// 256x1 PNG strip
+    Png,
+    /// JASC-PAL (.pal) with an alpha column
+    Jasc,
+    /// GIMP palette (.gpl) with an alpha column
+    Gpl,
+    /// Adobe Color Table (.act) with a transparent index
+    Act,
+    /// The ROM's big-endian RGBA5551 bytes (.bin)
+    Raw,
+}
+
+impl PaletteFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PaletteFormat::Png => "png",
+            PaletteFormat::Jasc => "pal",
+            PaletteFormat::Gpl => "gpl",
+            PaletteFormat::Act => "act",
+            PaletteFormat::Raw => "bin",
+        }
+    }
+}
+
+/// Converts an 8-bit channel back to the 5 bits it was expanded from.
+fn to_5bit(c: u8) -> u16 {
+    (c as u16 * 31 + 127) / 255
+}
+
+/// Encodes the palette back to the big-endian RGBA5551 words it was read
+/// from, alpha in the low bit.
+pub fn to_rgba5551(palette: &Palette) -> Vec<u8> {
+    let mut out = Vec::with_capacity(palette.colors.len() * 2);
+    for c in palette.colors.iter() {
+        let [r, g, b, a] = c.0;
+        let word = (to_5bit(r) << 11) | (to_5bit(g) << 6) | (to_5bit(b) << 1) | (a != 0) as u16;
+        out.write_u16::<BigEndian>(word).unwrap();
+    }
+    out
+}
+
+/// JASC-PAL, with the alpha as a fourth column the way Aseprite reads and
+/// writes it.
+pub fn to_jasc(palette: &Palette) -> String {
+    let mut out = format!("JASC-PAL\r\n0100\r\n{}\r\n", palette.colors.len());
+    for c in palette.colors.iter() {
+        let [r, g, b, a] = c.0;
+        out += &format!("{} {} {} {}\r\n", r, g, b, a);
+    }
+    out
+}
+
+/// GIMP palette with Aseprite's "Channels: RGBA" extension so the
+/// transparency bit survives. GIMP itself ignores the alpha column.
+pub fn to_gpl(palette: &Palette) -> String {
+    let mut out = format!("GIMP Palette\nName: palette_{:02}\nColumns: 16\nChannels: RGBA\n#\n", palette.index);
+    for (i, c) in palette.colors.iter().enumerate() {
+        let [r, g, b, a] = c.0;
+        out += &format!("{:3} {:3} {:3} {:3}\tIndex {}\n", r, g, b, a, i);
+    }
+    out
+}
+
+/// Adobe Color Table: 256 RGB triples followed by the color count and the
+/// transparent index. ACT only has one transparent index, so that's
+/// `Palette::transparent_index`, or 0xFFFF for none.
+pub fn to_act(palette: &Palette) -> Vec<u8> {
+    let mut out = Vec::with_capacity(256 * 3 + 4);
+    for i in 0..256 {
+        let rgb = palette.colors.get(i).map_or([0; 3], |c| [c.0[0], c.0[1], c.0[2]]);
+        out.extend_from_slice(&rgb);
+    }
+    out.write_u16::<BigEndian>(palette.colors.len().min(256) as u16).unwrap();
+    out.write_u16::<BigEndian>(palette.transparent_index().map_or(0xffff, |i| i as u16)).unwrap();
+    out
+}
+
+/// Writes `palette` to `path` in the given format. PNG is a 256x1 strip
+/// with the alpha bit as the alpha channel.
+pub fn write_palette(path: &Path, palette: &Palette, format: PaletteFormat) -> Result<()> {
+    let data = match format {
+        PaletteFormat::Png => {
+            let mut palimg = RgbaImage::new(palette.colors.len() as u32, 1);
+            for (x, col) in palette.colors.iter().enumerate() {
+                palimg.put_pixel(x as u32, 0, *col);
+            }
+            palimg.save(path)?;
+            return Ok(());
+        },
+        PaletteFormat::Jasc => to_jasc(palette).into_bytes(),
+        PaletteFormat::Gpl => to_gpl(palette).into_bytes(),
+        PaletteFormat::Act => to_act(palette),
+        PaletteFormat::Raw => to_rgba5551(palette),
+    };
+    fs::write(path, data).with_context(|| format!("Unable to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn act_transparent(palette: &Palette) -> u16 {
+        let act = to_act(palette);
+        assert_eq!(act.len(), 772);
+        u16::from_be_bytes([act[770], act[771]])
+    }
+
+    #[test]
+    fn act_marks_only_a_real_transparent_index() {
+        let mut palette = Palette { index: 0, colors: vec![Rgba([8, 16, 24, 255]); 256] };
+        assert_eq!(act_transparent(&palette), 0xffff);
+
+        palette.colors[5].0[3] = 0;
+        assert_eq!(act_transparent(&palette), 5);
+    }
+}
@@ -0,0 +1,284 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use crate::n64::RomHeader;
+
+/// Table locations and entry counts for one release of the game.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Profile {
+    pub name: String,
+
+    // headers
+    pub objinfo_offset: usize,
+    pub objinfo_count: usize,
+
+    // frame count header offset
+    pub defs_offset: usize,
+    pub defs_count: usize,
+
+    // frame data offset
+    pub frames_base_offset: usize,
+
+    // spi offsets offset
+    pub spi_offset_offset: usize,
+
+    // spi data offset
+    pub spi_base_offset: usize,
+    pub spi_count: usize,
+
+    // The palettes live in a code segment loaded from `segment_rom_offset`
+    // to `segment_ram_address`, so they're located by RAM address.
+    pub segment_rom_offset: usize,
+    pub segment_ram_address: usize,
+    pub palette_ram_address: usize,
+    pub palette_count: usize,
+}
+
+pub const OBJINFO_SIZE: usize = 0x10;
+pub const OBJDEF_SIZE: usize = 0x14;
+pub const FRAME_SIZE: usize = 0xE;
+pub const SPI_OFFSET_SIZE: usize = 8;
+pub const PALETTE_SIZE: usize = 0x200;
+
+impl Profile {
+    /// Wonder Project J2: Koruro no Mori no Jozet (Japan)
+    pub fn wonder_project_j2() -> Self {
+        Profile {
+            name: "Wonder Project J2 (Japan)".to_string(),
+            objinfo_offset: 0x000f27e0,
+            objinfo_count: 0x9B4,
+            defs_offset: 0x000fd180,
+            defs_count: 1645,
+            frames_base_offset: 0x00105220,
+            spi_offset_offset: 0x00133ac0,
+            spi_base_offset: 0x0013d2e0,
+            spi_count: 0x1303,
+            segment_rom_offset: 0x000f27e0,
+            segment_ram_address: 0x80400000,
+            palette_ram_address: 0x8078D1C0,
+            palette_count: 0x60,
+        }
+    }
+
+    pub fn palette_offset(&self, i: usize) -> usize {
+        self.segment_rom_offset + (self.palette_ram_address - self.segment_ram_address) + i * PALETTE_SIZE
+    }
+
+    /// Formats the profile as a TOML file that `load_overrides` reads back,
+    /// with addresses in hex.
+    pub fn to_toml(&self) -> String {
+        let addresses = [
+            ("objinfo_offset", self.objinfo_offset),
+            ("defs_offset", self.defs_offset),
+            ("frames_base_offset", self.frames_base_offset),
+            ("spi_offset_offset", self.spi_offset_offset),
+            ("spi_base_offset", self.spi_base_offset),
+            ("segment_rom_offset", self.segment_rom_offset),
+            ("segment_ram_address", self.segment_ram_address),
+            ("palette_ram_address", self.palette_ram_address),
+        ];
+        let counts = [
+            ("objinfo_count", self.objinfo_count),
+            ("defs_count", self.defs_count),
+            ("spi_count", self.spi_count),
+            ("palette_count", self.palette_count),
+        ];
+
+        let mut out = format!("name = {}\n", toml::Value::String(self.name.clone()));
+        for (field, value) in addresses.iter() {
+            out += &format!("{} = 0x{:08x}\n", field, value);
+        }
+        for (field, value) in counts.iter() {
+            out += &format!("{} = {}\n", field, value);
+        }
+        out
+    }
+
+    /// Checks that every fixed-size table lies inside the ROM, so a profile
+    /// that doesn't match the image fails here instead of panicking midway
+    /// through extraction.
+    pub fn validate(&self, rom: &[u8]) -> Result<()> {
+        if self.palette_ram_address < self.segment_ram_address {
+            return Err(anyhow!("{}: palette address {:#x} is below segment address {:#x}",
+                               self.name, self.palette_ram_address, self.segment_ram_address));
+        }
+
+        let palette_start = self.segment_rom_offset.checked_add(self.palette_ram_address - self.segment_ram_address);
+        let tables = [
+            ("ObjInfo table", Some(self.objinfo_offset), self.objinfo_count, OBJINFO_SIZE),
+            ("ObjDef table", Some(self.defs_offset), self.defs_count, OBJDEF_SIZE),
+            ("SPI offset table", Some(self.spi_offset_offset), self.spi_count, SPI_OFFSET_SIZE),
+            ("palette bank", palette_start, self.palette_count, PALETTE_SIZE),
+        ];
+
+        for &(what, start, count, size) in tables.iter() {
+            let end = start.zip(count.checked_mul(size)).and_then(|(start, size)| start.checked_add(size));
+            match (start, end) {
+                (Some(_), Some(end)) if end <= rom.len() => (),
+                (Some(start), Some(end)) =>
+                    return Err(anyhow!("{}: {} at {:#x}..{:#x} is past the end of the ROM ({:#x} bytes)",
+                                       self.name, what, start, end, rom.len())),
+                _ =>
+                    return Err(anyhow!("{}: {} of {} entries is out of range", self.name, what, count)),
+            }
+        }
+
+        if self.frames_base_offset > rom.len() || self.spi_base_offset > rom.len() {
+            return Err(anyhow!("{}: frame or SPI data base is past the end of the ROM", self.name));
+        }
+
+        Ok(())
+    }
+}
+
+struct KnownRom {
+    game_code: &'static str,
+    revision: u8,
+    profile: fn() -> Profile,
+}
+
+const KNOWN_ROMS: &[KnownRom] = &[
+    KnownRom { game_code: "NJ2J", revision: 0, profile: Profile::wonder_project_j2 },
+];
+
+/// Picks the offset profile matching a ROM's game code and revision.
+pub fn identify(header: &RomHeader) -> Result<Profile> {
+    KNOWN_ROMS.iter()
+        .find(|known| known.game_code == header.game_code && known.revision == header.revision)
+        .map(|known| (known.profile)())
+        .ok_or_else(|| anyhow!("Unsupported ROM \"{}\" ({} rev {}); only Wonder Project J2 (NJ2J rev 0) is known",
+                               header.title, header.game_code, header.revision))
+}
+
+/// Accepts either a plain integer or a string such as "0x133ac0", since JSON
+/// has no hex literals.
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Int(usize),
+        Str(String),
+    }
+
+    let parsed = match Number::deserialize(deserializer)? {
+        Number::Int(n) => n,
+        Number::Str(s) => {
+            let s = s.trim();
+            let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => s.parse(),
+            };
+            result.map_err(|e| serde::de::Error::custom(format!("invalid number {:?}: {}", s, e)))?
+        }
+    };
+    Ok(Some(parsed))
+}
+
+macro_rules! profile_overrides {
+    ($($field:ident),*) => {
+        /// A profile read from a TOML or JSON file. Any field left out is
+        /// taken from the profile of the identified ROM.
+        #[derive(Debug, Default, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        pub struct ProfileOverrides {
+            pub name: Option<String>,
+            $(
+                #[serde(default, deserialize_with = "number")]
+                pub $field: Option<usize>,
+            )*
+        }
+
+        impl ProfileOverrides {
+            /// Applies the overrides on top of `base`. Without a base every
+            /// field has to be given.
+            pub fn apply(self, base: Option<Profile>) -> Result<Profile> {
+                match base {
+                    Some(mut profile) => {
+                        if let Some(name) = self.name {
+                            profile.name = name;
+                        }
+                        $(
+                            if let Some(value) = self.$field {
+                                profile.$field = value;
+                            }
+                        )*
+                        Ok(profile)
+                    },
+                    None => {
+                        let missing: Vec<&str> = [$((stringify!($field), self.$field.is_none())),*]
+                            .iter()
+                            .filter(|(_, missing)| *missing)
+                            .map(|(field, _)| *field)
+                            .collect();
+                        if !missing.is_empty() {
+                            return Err(anyhow!("Profile for an unknown ROM is missing {}", missing.join(", ")));
+                        }
+                        Ok(Profile {
+                            name: self.name.unwrap_or_else(|| "Custom".to_string()),
+                            $($field: self.$field.unwrap()),*
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+profile_overrides!(
+    objinfo_offset, objinfo_count,
+    defs_offset, defs_count,
+    frames_base_offset,
+    spi_offset_offset, spi_base_offset, spi_count,
+    segment_rom_offset, segment_ram_address, palette_ram_address, palette_count
+);
+
+/// Reads a profile file, as JSON if it has a .json extension and as TOML
+/// otherwise.
+pub fn load_overrides(path: &Path) -> Result<ProfileOverrides> {
+    let text = fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let is_json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    let overrides = if is_json {
+        serde_json::from_str(&text).with_context(|| format!("Unable to parse {}", path.display()))?
+    } else {
+        toml::from_str(&text).with_context(|| format!("Unable to parse {}", path.display()))?
+    };
+    Ok(overrides)
+}
+
+/// Picks the profile for a ROM, applying the profile file at `path` if one
+/// is given. A complete profile file makes unknown ROMs usable too.
+pub fn resolve(header: &RomHeader, path: Option<&Path>) -> Result<Profile> {
+    let base = identify(header);
+    match path {
+        Some(path) => load_overrides(path)?.apply(base.ok()),
+        None => base,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_values_are_out_of_range() {
+        let rom = vec![0; 0x1000];
+        let small = Profile {
+            objinfo_offset: 0, objinfo_count: 0, defs_offset: 0, defs_count: 0,
+            spi_offset_offset: 0, spi_count: 0, frames_base_offset: 0, spi_base_offset: 0,
+            segment_rom_offset: 0, segment_ram_address: 0, palette_ram_address: 0, palette_count: 1,
+            ..Profile::wonder_project_j2()
+        };
+        small.validate(&rom).unwrap();
+
+        for profile in [
+            Profile { defs_count: usize::MAX / 2, ..small.clone() },
+            Profile { spi_offset_offset: usize::MAX, spi_count: 1, ..small.clone() },
+            Profile { segment_rom_offset: usize::MAX, palette_ram_address: 1, ..small.clone() },
+            Profile { segment_rom_offset: usize::MAX - PALETTE_SIZE / 2, ..small.clone() },
+        ] {
+            let err = profile.validate(&rom).unwrap_err().to_string();
+            assert!(err.contains("out of range"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use crate::dump::{FrameRecord, ObjDefRecord};
+use crate::obj;
+use crate::profile::{Profile, OBJDEF_SIZE, FRAME_SIZE, SPI_OFFSET_SIZE};
+use crate::spi::Spi;
+use crate::writeable::Writeable;
+
+// Relocated frame lists start on this boundary so their halfwords stay
+// aligned.
+const FRAME_ALIGN: usize = 2;
+
+// Relocated containers are appended to the end of the ROM on this boundary,
+// which also keeps the low "skip" bit of their table offset clear.
+const SPI_ALIGN: usize = 8;
+
+fn original_spi<'a>(profile: &Profile, buffer: &'a [u8], index: usize) -> Result<(usize, Spi<'a>)> {
+    if index >= profile.spi_count {
+        return Err(anyhow!("SPI index {} out of range (max {})", index, profile.spi_count as isize - 1));
+    }
+
+    let (entry, offset) = obj::spi_offset(profile, buffer, index)?;
+    let start = profile.spi_base_offset + offset as usize;
+    if start > buffer.len() {
+        return Err(anyhow!("SPI {} at {:#x} is past the end of the ROM", index, start));
+    }
+    let (_, spi) = crate::spi::spi(&buffer[start..])
+        .map_err(|e| anyhow!("Parsing failed! {:?}", e))?;
+    Ok((entry, spi))
+}
+
+/// Loads a replacement for SPI `index` from disk. Files starting with an
+/// "SPI0"/"SPI1" magic are taken as ready-made containers; anything else is
+/// treated as a decompressed stream and compressed with the same magic as
+/// the SPI it replaces.
+pub fn load_replacement(profile: &Profile, buffer: &[u8], index: usize, path: &Path) -> Result<Spi<'static>> {
+    let data = fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+
+    if data.starts_with(b"SPI0") || data.starts_with(b"SPI1") {
+        let (_, spi) = crate::spi::spi(&data).map_err(|e| anyhow!("Parsing {} failed! {:?}", path.display(), e))?;
+        return Ok(spi.into_owned());
+    }
+
+    let (_, original) = original_spi(profile, buffer, index)?;
+    crate::compress::compress_spi(&original.header.magic, &data)
+}
+
+/// Writes a new ROM with the given SPIs replaced and ObjDefs imported, and
+/// the header checksum updated to match; see `replace_spis`.
+pub fn rebuild(profile: &Profile, buffer: &[u8], replacements: &[(usize, Spi<'_>)], defs: &[ObjDefRecord]) -> Result<Vec<u8>> {
+    let mut rom = buffer.to_vec();
+
+    if !defs.is_empty() {
+        import_defs(profile, &mut rom, defs)?;
+    }
+    replace_spis(profile, &mut rom, replacements)?;
+
+    // Keep the image a whole number of words.
+    rom.resize(rom.len().next_multiple_of(4), 0);
+
+    crate::n64::fix_crc(&mut rom)?;
+
+    Ok(rom)
+}
+
+/// Rewrites the second word of a table entry for a container that went from
+/// `old_size` to `new_size` bytes. `obj::spi_offset` doesn't read it and
+/// what the game uses it for isn't known; it may be a DMA length. When it
+/// holds the old container's size, exact or padded to `SPI_ALIGN`, it gets
+/// the new size the same way. Anything else is left as it was.
+fn update_entry_size(entry: &mut [u8], old_size: usize, new_size: usize) {
+    let stored = BigEndian::read_u32(&entry[4..8]) as usize;
+    if stored == old_size {
+        BigEndian::write_u32(&mut entry[4..8], new_size as u32);
+    } else if stored == old_size.next_multiple_of(SPI_ALIGN) {
+        BigEndian::write_u32(&mut entry[4..8], new_size.next_multiple_of(SPI_ALIGN) as u32);
+    }
+}
+
+/// Replaces SPI containers in `rom`. Containers that fit in the space of
+/// the one they replace are written in place, unless another table entry
+/// points at the same container; larger or shared ones are appended to the
+/// end of the ROM, aligned relative to `spi_base_offset`. Either way the
+/// offset table entry is rewritten to point at the new data.
+///
+/// Note that several SPI indices can resolve to the same table entry, in
+/// which case replacing one replaces all of them.
+pub fn replace_spis(profile: &Profile, rom: &mut Vec<u8>, replacements: &[(usize, Spi<'_>)]) -> Result<()> {
+    for (index, spi) in replacements.iter() {
+        let (entry, original) = original_spi(profile, rom, *index)?;
+        let original_size = original.byte_size();
+        let ind = profile.spi_offset_offset + entry * SPI_OFFSET_SIZE;
+        let original_offset = BigEndian::read_u32(&rom[ind..ind+4]);
+        let original_start = profile.spi_base_offset + original_offset as usize;
+        let shared = (0..profile.spi_count)
+            .filter_map(|j| obj::spi_offset(profile, rom, j).ok())
+            .any(|(other, offset)| other != entry && offset == original_offset);
+
+        let mut bytes = Vec::with_capacity(spi.byte_size());
+        spi.write(&mut bytes)?;
+
+        // The offset is what has to stay aligned: its low bit marks an
+        // entry to skip.
+        let start = if bytes.len() <= original_size && !shared {
+            original_start
+        } else {
+            profile.spi_base_offset + (rom.len() - profile.spi_base_offset).next_multiple_of(SPI_ALIGN)
+        };
+        let end = start + bytes.len();
+        if end > rom.len() {
+            rom.resize(end, 0);
+        }
+        rom[start..end].copy_from_slice(&bytes);
+
+        let offset = u32::try_from(start - profile.spi_base_offset)
+            .map_err(|_| anyhow!("SPI {} relocated out of addressable range ({:#x})", index, start))?;
+        BigEndian::write_u32(&mut rom[ind..ind+4], offset);
+        update_entry_size(&mut rom[ind..ind+SPI_OFFSET_SIZE], original_size, bytes.len());
+    }
+
+    Ok(())
+}
+
+fn encode_frames(frames: &[FrameRecord]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frames.len() * FRAME_SIZE);
+    for frame in frames.iter() {
+        out.write_u16::<BigEndian>(frame.spi_idx).unwrap();
+        out.extend_from_slice(&[frame.kind, frame.id, frame.delay, frame.u2]);
+        out.write_i16::<BigEndian>(frame.x).unwrap();
+        out.write_i16::<BigEndian>(frame.y).unwrap();
+        out.write_u16::<BigEndian>(frame.u5).unwrap();
+        out.extend_from_slice(&[frame.u6, frame.u7]);
+    }
+    out
+}
+
+/// Overwrites ObjDefs and their frame lists with the given records.
+///
+/// A frame list is rewritten in place when it still fits and no other
+/// ObjDef's list overlaps it. Otherwise it moves to free space inside the frame
+/// table: space given up by lists that moved or shrank, and zero-filled
+/// gaps no ObjDef points into. The frame table is part of a segment the
+/// game copies to RAM, so lists can't simply be appended to the ROM.
+pub fn import_defs(profile: &Profile, rom: &mut [u8], records: &[ObjDefRecord]) -> Result<()> {
+    let tables = obj::parse_tables(profile, rom)?;
+    let base = profile.frames_base_offset;
+    let span = |offset: u32, count: usize| (offset as usize, offset as usize + count * FRAME_SIZE);
+
+    let mut edits = HashMap::new();
+    for record in records.iter() {
+        if record.index >= tables.defs.len() {
+            return Err(anyhow!("ObjDef {} out of range (max {})", record.index, tables.defs.len() as isize - 1));
+        }
+        if record.frames.len() > u8::MAX as usize {
+            return Err(anyhow!("ObjDef {} has {} frames; at most {} fit", record.index, record.frames.len(), u8::MAX));
+        }
+        if edits.insert(record.index, record).is_some() {
+            return Err(anyhow!("ObjDef {} is listed more than once", record.index));
+        }
+    }
+
+    // The frame table, as far as the ObjDefs reach into it.
+    let lists: Vec<(usize, usize)> = tables.defs.iter()
+        .filter(|def| def.frame_count > 0)
+        .map(|def| span(def.frames_offset, def.frame_count as usize))
+        .collect();
+    let region_start = lists.iter().map(|l| l.0).min().unwrap_or(0);
+    let region_end = lists.iter().map(|l| l.1).max().unwrap_or(0);
+
+    // Work out where each ObjDef's frames end up; None means it needs moving.
+    let mut placement: Vec<(Option<u32>, Vec<u8>)> = Vec::with_capacity(tables.defs.len());
+    for (i, def) in tables.defs.iter().enumerate() {
+        let (start, end) = span(def.frames_offset, def.frame_count as usize);
+        // An empty list's offset can be anything, even past the ROM.
+        let original = if def.frame_count > 0 { rom[base+start..base+end].to_vec() } else { Vec::new() };
+        let frames = match edits.get(&i) {
+            Some(record) => encode_frames(&record.frames),
+            None => {
+                placement.push((Some(def.frames_offset), original));
+                continue;
+            }
+        };
+
+        if frames == original || frames.is_empty() {
+            placement.push((Some(def.frames_offset), frames));
+            continue;
+        }
+
+        // Another ObjDef reading any of these bytes would see the edit, and
+        // would write its own copy back over it below.
+        let shared = tables.defs.iter().enumerate().any(|(j, other)| {
+            let (other_start, other_end) = span(other.frames_offset, other.frame_count as usize);
+            j != i && other.frame_count > 0 && other_start < end && start < other_end
+        });
+        if !shared && frames.len() <= original.len() {
+            placement.push((Some(def.frames_offset), frames));
+        } else {
+            placement.push((None, frames));
+        }
+    }
+
+    // Mark the frame table bytes that are free to reuse.
+    let mut used = vec![false; region_end - region_start];
+    for (offset, frames) in placement.iter() {
+        if let (Some(offset), false) = (offset, frames.is_empty()) {
+            let (start, _) = span(*offset, 0);
+            used[start-region_start..start-region_start+frames.len()].fill(true);
+        }
+    }
+    let mut free = vec![false; used.len()];
+    for &(start, end) in lists.iter() {
+        free[start-region_start..end-region_start].fill(true);
+    }
+    for (k, is_free) in free.iter_mut().enumerate() {
+        *is_free = !used[k] && (*is_free || rom[base+region_start+k] == 0);
+    }
+
+    for (i, (offset, frames)) in placement.iter_mut().enumerate() {
+        if offset.is_some() {
+            continue;
+        }
+        let found = (0..free.len().saturating_sub(frames.len() - 1))
+            .filter(|k| (region_start + k).is_multiple_of(FRAME_ALIGN))
+            .find(|&k| free[k..k+frames.len()].iter().all(|f| *f))
+            .ok_or_else(|| anyhow!("No room in the frame table ({:#x}..{:#x}) for the {} frames of ObjDef {}",
+                                   base + region_start, base + region_end, frames.len() / FRAME_SIZE, i))?;
+        free[found..found+frames.len()].fill(false);
+        *offset = Some((region_start + found) as u32);
+    }
+
+    for (i, (offset, frames)) in placement.iter().enumerate() {
+        if !frames.is_empty() {
+            let start = base + offset.unwrap() as usize;
+            rom[start..start+frames.len()].copy_from_slice(frames);
+        }
+
+        let ind = profile.defs_offset + i * OBJDEF_SIZE;
+        BigEndian::write_u32(&mut rom[ind..ind+4], offset.unwrap());
+        rom[ind+16] = (frames.len() / FRAME_SIZE) as u8;
+        if let Some(record) = edits.get(&i) {
+            BigEndian::write_u16(&mut rom[ind+4..ind+6], record.u1);
+            BigEndian::write_u16(&mut rom[ind+6..ind+8], record.u2);
+            BigEndian::write_u16(&mut rom[ind+8..ind+10], record.u3);
+            BigEndian::write_u16(&mut rom[ind+10..ind+12], record.u4);
+            BigEndian::write_u32(&mut rom[ind+12..ind+16], record.u5);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFS: usize = 0x100;
+    const FRAMES: usize = 0x400;
+
+    fn test_profile(defs_count: usize) -> Profile {
+        Profile {
+            name: "test".to_string(),
+            objinfo_offset: 0,
+            objinfo_count: 0,
+            defs_offset: DEFS,
+            defs_count,
+            frames_base_offset: FRAMES,
+            spi_offset_offset: 0,
+            spi_base_offset: 0,
+            spi_count: 0,
+            segment_rom_offset: 0,
+            segment_ram_address: 0,
+            palette_ram_address: 0,
+            palette_count: 0,
+        }
+    }
+
+    /// A ROM whose ObjDef `i` has `delays[i].len()` frames at `offsets[i]`,
+    /// each frame tagged by its delay.
+    fn test_rom(offsets: &[u32], delays: &[&[u8]]) -> Vec<u8> {
+        let mut rom = vec![0; 0x1000];
+        for (i, (&offset, frames)) in offsets.iter().zip(delays.iter()).enumerate() {
+            let ind = DEFS + i * OBJDEF_SIZE;
+            BigEndian::write_u32(&mut rom[ind..ind+4], offset);
+            rom[ind+16] = frames.len() as u8;
+            for (j, &delay) in frames.iter().enumerate() {
+                let at = FRAMES + offset as usize + j * FRAME_SIZE;
+                BigEndian::write_u16(&mut rom[at..at+2], j as u16 + 1);
+                rom[at+4] = delay;
+            }
+        }
+        rom
+    }
+
+    const SPI_TABLE: usize = 0x40;
+    // Odd on purpose: offsets have to stay even relative to it.
+    const SPI_BASE: usize = 0x1001;
+
+    /// A ROM with SPI `i` filled with byte `i`, each entry's second word
+    /// holding its container's size.
+    fn spi_rom(sizes: &[usize]) -> (Profile, Vec<u8>) {
+        let profile = Profile { spi_offset_offset: SPI_TABLE, spi_base_offset: SPI_BASE, spi_count: sizes.len(), ..test_profile(0) };
+        let mut rom = vec![0; SPI_BASE];
+        for (i, &size) in sizes.iter().enumerate() {
+            let spi = crate::compress::compress_spi0(&vec![i as u8; size]);
+            let ind = SPI_TABLE + i * SPI_OFFSET_SIZE;
+            let offset = (rom.len() - SPI_BASE) as u32;
+            BigEndian::write_u32(&mut rom[ind..ind+4], offset);
+            BigEndian::write_u32(&mut rom[ind+4..ind+8], spi.byte_size() as u32);
+            spi.write(&mut rom).unwrap();
+            rom.resize(SPI_BASE + (rom.len() - SPI_BASE).next_multiple_of(SPI_ALIGN), 0);
+        }
+        (profile, rom)
+    }
+
+    fn spi_contents(profile: &Profile, rom: &[u8], i: usize) -> (u32, u32, Vec<u8>) {
+        let ind = SPI_TABLE + i * SPI_OFFSET_SIZE;
+        let spi = obj::Spis::new(profile, rom).get(i).unwrap();
+        assert_eq!(BigEndian::read_u32(&rom[ind+4..ind+8]) as usize, spi.byte_size());
+        (BigEndian::read_u32(&rom[ind..ind+4]), BigEndian::read_u32(&rom[ind+4..ind+8]), crate::convert::decompress_spi(&spi).unwrap())
+    }
+
+    #[test]
+    fn smaller_spi_is_replaced_in_place() {
+        let (profile, mut rom) = spi_rom(&[0x40, 0x40]);
+        let len = rom.len();
+        let offset = spi_contents(&profile, &rom, 1).0;
+        let spi = crate::compress::compress_spi0(&[7; 0x10]);
+        replace_spis(&profile, &mut rom, &[(1, spi.clone())]).unwrap();
+
+        assert_eq!(rom.len(), len);
+        assert_eq!(spi_contents(&profile, &rom, 1), (offset, spi.byte_size() as u32, vec![7; 0x10]));
+        assert_eq!(spi_contents(&profile, &rom, 0).2, vec![0; 0x40]);
+    }
+
+    #[test]
+    fn larger_spi_is_appended_aligned_to_base() {
+        let (profile, mut rom) = spi_rom(&[0x40, 0x40]);
+        rom.push(0xaa);
+        let len = rom.len();
+        let data: Vec<u8> = (0..0x400).map(|b| (b * 7 % 251) as u8).collect();
+        let spi = crate::compress::compress_spi0(&data);
+        replace_spis(&profile, &mut rom, &[(0, spi.clone())]).unwrap();
+
+        let (offset, size, decomp) = spi_contents(&profile, &rom, 0);
+        assert_eq!(offset as usize, (len - SPI_BASE).next_multiple_of(SPI_ALIGN));
+        assert_eq!(size as usize, spi.byte_size());
+        assert_eq!(decomp, data);
+        assert_eq!(spi_contents(&profile, &rom, 1).2, vec![1; 0x40]);
+    }
+
+    #[test]
+    fn shared_spi_is_appended() {
+        let (profile, mut rom) = spi_rom(&[0x40, 0x40]);
+        rom.copy_within(SPI_TABLE..SPI_TABLE+SPI_OFFSET_SIZE, SPI_TABLE + SPI_OFFSET_SIZE);
+        let len = rom.len();
+        let spi = crate::compress::compress_spi0(&[7; 0x10]);
+        replace_spis(&profile, &mut rom, &[(1, spi.clone())]).unwrap();
+
+        let (offset, _, decomp) = spi_contents(&profile, &rom, 1);
+        assert_eq!(offset as usize, (len - SPI_BASE).next_multiple_of(SPI_ALIGN));
+        assert_eq!(decomp, vec![7; 0x10]);
+        let (offset, _, decomp) = spi_contents(&profile, &rom, 0);
+        assert_eq!((offset, decomp), (0, vec![0; 0x40]));
+    }
+
+    fn record(index: usize, delays: &[u8]) -> ObjDefRecord {
+        let frames = delays.iter().enumerate()
+            .map(|(j, &delay)| FrameRecord {
+                index: j, rom_offset: 0, spi_idx: j as u16 + 1, kind: 0, id: 0, delay,
+                u2: 0, x: 0, y: 0, u5: 0, u6: 0, u7: 0,
+            })
+            .collect();
+        ObjDefRecord { index, rom_offset: 0, frames_offset: 0, u1: 0, u2: 0, u3: 0, u4: 0, u5: 0, frames }
+    }
+
+    fn delays(profile: &Profile, rom: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        obj::parse_tables(profile, rom).unwrap().defs.iter()
+            .map(|def| (def.frames_offset, def.frames.iter().map(|f| f.delay).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_unshared_list_in_place() {
+        let profile = test_profile(2);
+        let mut rom = test_rom(&[0, 0x2a], &[&[10, 11, 12], &[20, 21]]);
+        import_defs(&profile, &mut rom, &[record(0, &[50, 51])]).unwrap();
+        assert_eq!(delays(&profile, &rom), vec![(0, vec![50, 51]), (0x2a, vec![20, 21])]);
+    }
+
+    #[test]
+    fn grown_list_moves_to_free_space() {
+        let profile = test_profile(3);
+        let mut rom = test_rom(&[0, 0x2a, 0xa8], &[&[10, 11, 12], &[20, 21], &[30]]);
+        import_defs(&profile, &mut rom, &[record(1, &[50, 51, 52, 53])]).unwrap();
+        assert_eq!(delays(&profile, &rom), vec![
+            (0, vec![10, 11, 12]),
+            (0x2a, vec![50, 51, 52, 53]),
+            (0xa8, vec![30]),
+        ]);
+    }
+
+    #[test]
+    fn shared_list_is_copied() {
+        let profile = test_profile(3);
+        let mut rom = test_rom(&[0, 0, 0xa8], &[&[10, 11, 12], &[10, 11, 12], &[30]]);
+        import_defs(&profile, &mut rom, &[record(0, &[50, 51, 52])]).unwrap();
+        assert_eq!(delays(&profile, &rom), vec![
+            (0x2a, vec![50, 51, 52]),
+            (0, vec![10, 11, 12]),
+            (0xa8, vec![30]),
+        ]);
+    }
+
+    #[test]
+    fn overlapping_list_is_copied() {
+        let profile = test_profile(3);
+        let mut rom = test_rom(&[0, 0xe, 0xa8], &[&[10, 11, 12], &[11, 12], &[30]]);
+        import_defs(&profile, &mut rom, &[record(0, &[50, 51, 52])]).unwrap();
+        assert_eq!(delays(&profile, &rom), vec![
+            (0x2a, vec![50, 51, 52]),
+            (0xe, vec![11, 12]),
+            (0xa8, vec![30]),
+        ]);
+    }
+
+    #[test]
+    fn empty_list_after_region_is_ignored() {
+        let profile = test_profile(2);
+        let mut rom = test_rom(&[0, 0x200], &[&[10, 11, 12], &[]]);
+        import_defs(&profile, &mut rom, &[record(0, &[50, 51])]).unwrap();
+        assert_eq!(delays(&profile, &rom), vec![(0, vec![50, 51]), (0x200, vec![])]);
+    }
+
+    #[test]
+    fn empty_list_before_region_is_ignored() {
+        let profile = test_profile(2);
+        let mut rom = test_rom(&[0x10, 0], &[&[10, 11, 12], &[]]);
+        import_defs(&profile, &mut rom, &[record(0, &[50, 51])]).unwrap();
+        assert_eq!(delays(&profile, &rom), vec![(0x10, vec![50, 51]), (0, vec![])]);
+    }
+
+    #[test]
+    fn empty_list_past_rom_end_is_ignored() {
+        let profile = test_profile(2);
+        let mut rom = test_rom(&[0, 0x8000_0000], &[&[10, 11, 12], &[]]);
+        import_defs(&profile, &mut rom, &[record(0, &[50, 51])]).unwrap();
+        assert_eq!(delays(&profile, &rom), vec![(0, vec![50, 51]), (0x8000_0000, vec![])]);
+    }
+
+    #[test]
+    fn full_frame_table_is_an_error() {
+        let profile = test_profile(2);
+        let mut rom = test_rom(&[0, 0x2a], &[&[10, 11, 12], &[20, 21]]);
+        assert!(import_defs(&profile, &mut rom, &[record(1, &[50, 51, 52, 53])]).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use image::RgbaImage;
+use crate::cache::SpiCache;
+use crate::convert::{self, AnimLayout};
+use crate::n64::{self, RomFormat};
+use crate::obj::{self, ObjDef, ObjInfo, Palette, Spis, Tables};
+use crate::profile::{self, Profile};
+use crate::spi::Spi;
+
+/// Reads a ROM image and converts it to .z64 byte order. Returns the
+/// format it was in.
+pub fn read_rom(path: &Path) -> Result<(Vec<u8>, RomFormat)> {
+    let mut buffer = fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let format = n64::normalize(&mut buffer).context("Unable to detect ROM byte order")?;
+    Ok((buffer, format))
+}
+
+/// Picks the profile for a ROM from its header, applying the profile file
+/// at `profile_path` if one is given.
+pub fn identify(buffer: &[u8], profile_path: Option<&Path>) -> Result<Profile> {
+    let header = n64::header(buffer)?;
+    profile::resolve(&header, profile_path)
+}
+
+/// A loaded ROM with its tables parsed. SPIs are read from the ROM data
+/// when asked for.
+pub struct Rom {
+    data: Vec<u8>,
+    format: RomFormat,
+    profile: Profile,
+    tables: Tables,
+    cache: SpiCache,
+}
+
+impl Rom {
+    /// Reads a ROM in any byte order and parses it with the profile for its
+    /// header.
+    pub fn open(path: &Path) -> Result<Rom> {
+        Rom::open_with_profile(path, None)
+    }
+
+    /// Like `open`, applying the profile file at `profile_path` if one is
+    /// given; see `identify`.
+    pub fn open_with_profile(path: &Path, profile_path: Option<&Path>) -> Result<Rom> {
+        let (data, format) = read_rom(path)?;
+        let profile = identify(&data, profile_path)?;
+        let tables = obj::parse_tables(&profile, &data)?;
+        Ok(Rom { data, format, profile, tables, cache: SpiCache::new() })
+    }
+
+    /// Parses a ROM image in any byte order. Without a profile, the one for
+    /// the ROM's header is used.
+    pub fn from_bytes(mut data: Vec<u8>, profile: Option<Profile>) -> Result<Rom> {
+        let format = n64::normalize(&mut data).context("Unable to detect ROM byte order")?;
+        let profile = match profile {
+            Some(profile) => profile,
+            None => identify(&data, None)?,
+        };
+        let tables = obj::parse_tables(&profile, &data)?;
+        Ok(Rom { data, format, profile, tables, cache: SpiCache::new() })
+    }
+
+    /// The ROM in .z64 byte order.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The byte order the ROM was read in.
+    pub fn format(&self) -> RomFormat {
+        self.format
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub fn tables(&self) -> &Tables {
+        &self.tables
+    }
+
+    pub fn objinfos(&self) -> &[ObjInfo] {
+        &self.tables.objinfos
+    }
+
+    pub fn objdefs(&self) -> &[ObjDef] {
+        &self.tables.defs
+    }
+
+    pub fn objdef(&self, i: usize) -> Result<&ObjDef> {
+        self.tables.defs.get(i)
+            .ok_or_else(|| anyhow!("ObjDef {} out of range (max {})", i, self.tables.defs.len() as isize - 1))
+    }
+
+    pub fn spis(&self) -> Spis<'_> {
+        Spis::new(&self.profile, &self.data)
+    }
+
+    pub fn spi_count(&self) -> usize {
+        self.tables.spi_count
+    }
+
+    /// SPI `i`, parsed in place without touching the rest of the table.
+    pub fn spi(&self, i: usize) -> Result<Spi<'_>> {
+        self.spis().get(i)
+    }
+
+    pub fn palettes(&self) -> &[Palette] {
+        &self.tables.palettes
+    }
+
+    pub fn palette(&self, i: usize) -> Result<&Palette> {
+        self.tables.palette(i)
+    }
+
+    /// The chunk stream of SPI `i`; see `convert::render_spi`. Each SPI is
+    /// only decompressed once per `Rom`.
+    pub fn decompress_spi(&self, i: usize) -> Result<Arc<Vec<u8>>> {
+        self.cache.get(&self.spis(), i)
+    }
+
+    /// SPI `i` drawn with palette `palette`, or None if it has no pixels.
+    pub fn render_spi(&self, i: usize, palette: usize) -> Result<Option<RgbaImage>> {
+        Ok(convert::render_spi(&self.decompress_spi(i)?, self.palette(palette)?))
+    }
+
+    /// Where each frame of ObjDef `i` goes, with the SPIs it draws
+    /// decompressed.
+    pub fn layout_anim(&self, i: usize) -> Result<AnimLayout> {
+        convert::layout_anim(self.objdef(i)?, &self.spis(), &self.cache)
+    }
+}
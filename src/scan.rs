@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use byteorder::{ByteOrder, BigEndian};
+use crate::profile::{Profile, SPI_OFFSET_SIZE};
+use crate::spi::Spi;
+
+// Bases tried when looking for the offset table. The table's offsets are
+// relative to the first SPI in the block, which should be one of the first
+// few hits unless the ROM has stray SPIs elsewhere.
+const MAX_BASE_CANDIDATES: usize = 8;
+const MIN_TABLE_ENTRIES: usize = 8;
+// Longest stretch of skipped entries a table run may contain. Without a
+// limit every word of 0xFF padding starts a run to the end of the ROM.
+const MAX_SKIPPED_ENTRIES: usize = 4;
+
+pub struct ScanHit<'a> {
+    pub offset: usize,
+    pub spi: Spi<'a>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SpiTable {
+    pub offset_offset: usize,
+    pub base_offset: usize,
+    pub count: usize,
+}
+
+/// Finds every "SPI0"/"SPI1" magic in the ROM whose header parses and whose
+/// payload decompresses to exactly the size the header claims.
+pub fn find_spis(rom: &[u8]) -> Vec<ScanHit<'_>> {
+    let mut hits = Vec::new();
+
+    for offset in 0..rom.len().saturating_sub(4) {
+        if &rom[offset..offset+3] != b"SPI" || (rom[offset+3] != b'0' && rom[offset+3] != b'1') {
+            continue;
+        }
+
+        let spi = match crate::spi::spi(&rom[offset..]) {
+            Ok((_, spi)) => spi,
+            Err(_) => continue,
+        };
+        if spi.header.u1 == 0 || spi.data.is_empty() {
+            continue;
+        }
+
+        match crate::convert::decompress_spi(&spi) {
+            Ok(decomp) if decomp.len() == spi.header.u1 => hits.push(ScanHit { offset, spi }),
+            _ => continue,
+        }
+    }
+
+    hits
+}
+
+/// The run of table entries at `start` that point at known SPIs in
+/// increasing order, as (entries, SPIs matched). Entries with the low bit
+/// set are skipped over the same way `obj::spi_offset` does, up to
+/// `MAX_SKIPPED_ENTRIES` in a row, but a run can't end on one.
+fn table_run(rom: &[u8], start: usize, offsets: &HashSet<u32>) -> (usize, usize) {
+    let mut count = 0;
+    let mut matched = 0;
+    let mut skipped = 0;
+    let mut ind = start;
+    let mut k = 0;
+    let mut last = None;
+    while ind + SPI_OFFSET_SIZE <= rom.len() {
+        let value = BigEndian::read_u32(&rom[ind..ind+4]);
+        if value & 1 == 0 {
+            if !offsets.contains(&value) || last.is_some_and(|last| value <= last) {
+                break;
+            }
+            last = Some(value);
+            count = k + 1;
+            matched += 1;
+            skipped = 0;
+        } else {
+            skipped += 1;
+            if skipped > MAX_SKIPPED_ENTRIES {
+                break;
+            }
+        }
+        ind += SPI_OFFSET_SIZE;
+        k += 1;
+    }
+    (count, matched)
+}
+
+/// Infers where the SPI offset table and the data it's relative to are,
+/// by looking for the run of 8-byte entries that points at the most SPIs
+/// found by `find_spis`.
+pub fn find_spi_table(rom: &[u8], hits: &[ScanHit]) -> Option<SpiTable> {
+    let mut best: Option<(usize, SpiTable)> = None;
+
+    for base in hits.iter().take(MAX_BASE_CANDIDATES).map(|hit| hit.offset) {
+        let offsets: HashSet<u32> = hits.iter()
+            .filter(|hit| hit.offset >= base)
+            .filter_map(|hit| u32::try_from(hit.offset - base).ok())
+            .collect();
+
+        for start in (0..rom.len()).step_by(4) {
+            let (count, matched) = table_run(rom, start, &offsets);
+            if matched >= MIN_TABLE_ENTRIES && best.is_none_or(|(m, _)| matched > m) {
+                best = Some((matched, SpiTable { offset_offset: start, base_offset: base, count }));
+            }
+        }
+    }
+
+    best.map(|(_, table)| table)
+}
+
+/// Copies the located SPI table into `base`, leaving every other field as
+/// it was.
+pub fn apply_table(mut base: Profile, table: &SpiTable) -> Profile {
+    base.spi_offset_offset = table.offset_offset;
+    base.spi_base_offset = table.base_offset;
+    base.spi_count = table.count;
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writeable::Writeable;
+
+    const TABLE: usize = 0x100;
+    const FAKE: usize = 0x400;
+    const BASE: usize = 0x1000;
+    const SPIS: usize = 12;
+
+    /// A ROM with `SPIS` containers 0x100 apart from `BASE`, their offset
+    /// table at `TABLE`, and 64 KiB of 0xFF padding after them.
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0; BASE + SPIS * 0x100];
+        for i in 0..SPIS {
+            let spi = crate::compress::compress_spi0(&[i as u8; 0x40]);
+            let mut bytes = Vec::new();
+            spi.write(&mut bytes).unwrap();
+            let start = BASE + i * 0x100;
+            rom[start..start+bytes.len()].copy_from_slice(&bytes);
+            BigEndian::write_u32(&mut rom[TABLE+i*SPI_OFFSET_SIZE..], (i * 0x100) as u32);
+        }
+        rom.resize(rom.len() + 0x10000, 0xff);
+        rom
+    }
+
+    #[test]
+    fn finds_table_past_padding() {
+        let rom = test_rom();
+        let hits = find_spis(&rom);
+        assert_eq!(hits.len(), SPIS);
+        assert_eq!(find_spi_table(&rom, &hits), Some(SpiTable { offset_offset: TABLE, base_offset: BASE, count: SPIS }));
+    }
+
+    #[test]
+    fn skipped_entries_dont_outrank_matches() {
+        // A run that spans more entries than the real table by skipping
+        // odd words, but only points at 8 SPIs.
+        let mut rom = test_rom();
+        let mut ind = FAKE;
+        for i in 0..8 {
+            for _ in 0..MAX_SKIPPED_ENTRIES {
+                BigEndian::write_u32(&mut rom[ind..], 0xffff_ffff);
+                ind += SPI_OFFSET_SIZE;
+            }
+            BigEndian::write_u32(&mut rom[ind..], (i * 0x100) as u32);
+            ind += SPI_OFFSET_SIZE;
+        }
+
+        let hits = find_spis(&rom);
+        assert_eq!(find_spi_table(&rom, &hits).map(|t| t.offset_offset), Some(TABLE));
+    }
+}
@@ -1,8 +1,8 @@
-use std::io::{self, Read, Write};
+use std::borrow::Cow;
+use std::io::{self, Write};
 use nom::{ToUsize, IResult};
-use nom::number::streaming::{be_u8, be_u32};
+use nom::number::streaming::be_u32;
 use nom::bytes::streaming::*;
-use nom::multi::count;
 use crate::writeable::Writeable;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -20,7 +20,7 @@ pub fn spi_header(input: &[u8]) -> IResult<&[u8], SpiHeader> {
     let (input, u2) = be_u32(input).map(|(i, u)| (i, u.to_usize()))?;
     let (input, u3) = be_u32(input).map(|(i, u)| (i, u.to_usize()))?;
     let (input, u4) = be_u32(input).map(|(i, u)| (i, u.to_usize()))?;
-    Ok((input, SpiHeader { magic: std::str::from_utf8(magic).unwrap().to_string(), u1, u2, u3, u4 }))
+    Ok((input, SpiHeader { magic: String::from_utf8_lossy(magic).to_string(), u1, u2, u3, u4 }))
 }
 
 impl Writeable for SpiHeader {
@@ -41,37 +41,43 @@ impl Writeable for SpiHeader {
     }
 }
 
+/// An SPI container. Parsed containers borrow their payload from the ROM;
+/// compressed ones own it.
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Spi {
+pub struct Spi<'a> {
     pub header: SpiHeader,
-    pub data: Vec<u8>
+    pub data: Cow<'a, [u8]>
 }
 
-impl Spi {
+impl Spi<'_> {
     pub fn slices(&self) -> (&[u8], &[u8], &[u8]) {
         let u2 = self.header.u2;
         let u3 = self.header.u3;
         let u4 = self.header.u4;
         (&self.data[0..u2], &self.data[u2..u2+u3], &self.data[u2+u3..u2+u3+u4])
     }
+
+    /// Detaches the container from the buffer it was parsed from.
+    pub fn into_owned(self) -> Spi<'static> {
+        Spi { header: self.header, data: Cow::Owned(self.data.into_owned()) }
+    }
 }
 
-pub fn spi(input: &[u8]) -> IResult<&[u8], Spi>{
+pub fn spi(input: &[u8]) -> IResult<&[u8], Spi<'_>> {
     let (input, header) = spi_header(input)?;
     let data_size = header.u2 + header.u3 + header.u4;
-    // println!("data size: {}", data_size);
-    let (input, data) = count(be_u8, data_size)(input)?;
-    Ok((input, Spi { header, data }))
+    let (input, data) = take(data_size)(input)?;
+    Ok((input, Spi { header, data: Cow::Borrowed(data) }))
 }
 
-impl Writeable for Spi {
+impl Writeable for Spi<'_> {
     fn byte_size(&self) -> usize {
         self.header.byte_size()
-            + self.data.byte_size()
+            + self.data.len()
     }
 
     fn write<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
         self.header.write(writer)?;
-        self.data.write(writer)
+        writer.write_all(&self.data)
     }
 }
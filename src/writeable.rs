@@ -1,6 +1,6 @@
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::mem;
-use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, WriteBytesExt};
 
 pub trait Writeable {
     fn byte_size(&self) -> usize;
@@ -39,7 +39,7 @@ impl Writeable for u32 {
 
 impl Writeable for usize {
     fn byte_size(&self) -> usize {
-        mem::size_of::<usize>()
+        mem::size_of::<u32>()
     }
 
     fn write<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
@@ -66,18 +66,18 @@ impl Writeable for &str {
     }
 
     fn write<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
-        writer.write_all(&self.as_bytes())
+        writer.write_all(self.as_bytes())
     }
 }
 
 impl Writeable for String {
     fn byte_size(&self) -> usize {
-        let s: &str = &self;
+        let s: &str = self;
         s.byte_size()
     }
 
     fn write<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
-        let s: &str = &self;
+        let s: &str = self;
         s.write(writer)
     }
 }